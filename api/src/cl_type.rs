@@ -0,0 +1,343 @@
+use std::collections::BTreeMap;
+
+#[cfg(not(target_arch = "wasm32"))]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Serialize, Deserialize))]
+pub enum CLType {
+    Bool,
+    String,
+    Unit,
+    Any,
+    U8,
+    U32,
+    U64,
+    I32,
+    I64,
+    ByteArray(u32),
+    Option(Box<CLType>),
+    List(Box<CLType>),
+    Map {
+        key: Box<CLType>,
+        value: Box<CLType>,
+    },
+    Tuple(Vec<CLType>),
+}
+
+/// Version of the binary encoding produced by [`CLType::encode`].
+///
+/// Every encoded type starts with this byte, and the tag values below never change meaning within
+/// a version.
+pub const CL_TYPE_ENCODING_VERSION: u8 = 1;
+
+const TAG_BOOL: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_UNIT: u8 = 2;
+const TAG_ANY: u8 = 3;
+const TAG_U8: u8 = 4;
+const TAG_U32: u8 = 5;
+const TAG_U64: u8 = 6;
+const TAG_I32: u8 = 7;
+const TAG_I64: u8 = 8;
+const TAG_BYTE_ARRAY: u8 = 9;
+const TAG_OPTION: u8 = 10;
+const TAG_LIST: u8 = 11;
+const TAG_MAP: u8 = 12;
+const TAG_TUPLE: u8 = 13;
+
+/// Deepest nesting of types accepted by [`CLType::decode`], which recurses once per level.
+pub const MAX_DECODE_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    UnsupportedVersion(u8),
+    UnknownTag(u8),
+    UnexpectedEnd,
+    TrailingBytes,
+    /// Types are nested deeper than [`MAX_DECODE_DEPTH`].
+    TooDeep,
+}
+
+impl CLType {
+    /// Encodes the type as a version byte followed by the type tree in prefix order.
+    ///
+    /// Each node is a one byte tag; `ByteArray` is followed by its length and `Tuple` by its number
+    /// of elements, both as little-endian `u32`. Nested types follow their parent.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![CL_TYPE_ENCODING_VERSION];
+        self.encode_into(&mut bytes);
+        bytes
+    }
+
+    fn encode_into(&self, bytes: &mut Vec<u8>) {
+        match self {
+            CLType::Bool => bytes.push(TAG_BOOL),
+            CLType::String => bytes.push(TAG_STRING),
+            CLType::Unit => bytes.push(TAG_UNIT),
+            CLType::Any => bytes.push(TAG_ANY),
+            CLType::U8 => bytes.push(TAG_U8),
+            CLType::U32 => bytes.push(TAG_U32),
+            CLType::U64 => bytes.push(TAG_U64),
+            CLType::I32 => bytes.push(TAG_I32),
+            CLType::I64 => bytes.push(TAG_I64),
+            CLType::ByteArray(len) => {
+                bytes.push(TAG_BYTE_ARRAY);
+                bytes.extend_from_slice(&len.to_le_bytes());
+            }
+            CLType::Option(inner) => {
+                bytes.push(TAG_OPTION);
+                inner.encode_into(bytes);
+            }
+            CLType::List(inner) => {
+                bytes.push(TAG_LIST);
+                inner.encode_into(bytes);
+            }
+            CLType::Map { key, value } => {
+                bytes.push(TAG_MAP);
+                key.encode_into(bytes);
+                value.encode_into(bytes);
+            }
+            CLType::Tuple(items) => {
+                bytes.push(TAG_TUPLE);
+                bytes.extend_from_slice(&(items.len() as u32).to_le_bytes());
+                for item in items {
+                    item.encode_into(bytes);
+                }
+            }
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<CLType, DecodeError> {
+        let (&version, mut rest) = bytes.split_first().ok_or(DecodeError::UnexpectedEnd)?;
+        if version != CL_TYPE_ENCODING_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let cl_type = Self::decode_from(&mut rest, 0)?;
+        if !rest.is_empty() {
+            return Err(DecodeError::TrailingBytes);
+        }
+        Ok(cl_type)
+    }
+
+    fn decode_from(bytes: &mut &[u8], depth: usize) -> Result<CLType, DecodeError> {
+        if depth == MAX_DECODE_DEPTH {
+            return Err(DecodeError::TooDeep);
+        }
+        let depth = depth + 1;
+        let cl_type = match take_u8(bytes)? {
+            TAG_BOOL => CLType::Bool,
+            TAG_STRING => CLType::String,
+            TAG_UNIT => CLType::Unit,
+            TAG_ANY => CLType::Any,
+            TAG_U8 => CLType::U8,
+            TAG_U32 => CLType::U32,
+            TAG_U64 => CLType::U64,
+            TAG_I32 => CLType::I32,
+            TAG_I64 => CLType::I64,
+            TAG_BYTE_ARRAY => CLType::ByteArray(take_u32(bytes)?),
+            TAG_OPTION => CLType::Option(Box::new(Self::decode_from(bytes, depth)?)),
+            TAG_LIST => CLType::List(Box::new(Self::decode_from(bytes, depth)?)),
+            TAG_MAP => CLType::Map {
+                key: Box::new(Self::decode_from(bytes, depth)?),
+                value: Box::new(Self::decode_from(bytes, depth)?),
+            },
+            TAG_TUPLE => {
+                let len = take_u32(bytes)?;
+                let items = (0..len)
+                    .map(|_| Self::decode_from(bytes, depth))
+                    .collect::<Result<_, _>>()?;
+                CLType::Tuple(items)
+            }
+            other => return Err(DecodeError::UnknownTag(other)),
+        };
+        Ok(cl_type)
+    }
+
+    /// Value tag stored by the host next to values of this type.
+    ///
+    /// This is the 64-bit FNV-1a hash of [`CLType::encode`], so it changes together with the
+    /// encoding version.
+    pub fn tag(&self) -> u64 {
+        const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const PRIME: u64 = 0x100000001b3;
+        self.encode().iter().fold(OFFSET_BASIS, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+        })
+    }
+}
+
+fn take_u8(bytes: &mut &[u8]) -> Result<u8, DecodeError> {
+    let (&byte, rest) = bytes.split_first().ok_or(DecodeError::UnexpectedEnd)?;
+    *bytes = rest;
+    Ok(byte)
+}
+
+fn take_u32(bytes: &mut &[u8]) -> Result<u32, DecodeError> {
+    if bytes.len() < 4 {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+    let (value, rest) = bytes.split_at(4);
+    *bytes = rest;
+    Ok(u32::from_le_bytes(value.try_into().unwrap()))
+}
+
+pub trait CLTyped {
+    fn cl_type() -> CLType;
+}
+
+impl CLTyped for String {
+    fn cl_type() -> CLType {
+        CLType::String
+    }
+}
+impl CLTyped for bool {
+    fn cl_type() -> CLType {
+        CLType::Bool
+    }
+}
+
+impl CLTyped for () {
+    fn cl_type() -> CLType {
+        CLType::Unit
+    }
+}
+
+impl CLTyped for u8 {
+    fn cl_type() -> CLType {
+        CLType::U8
+    }
+}
+
+impl CLTyped for u32 {
+    fn cl_type() -> CLType {
+        CLType::U32
+    }
+}
+
+impl CLTyped for u64 {
+    fn cl_type() -> CLType {
+        CLType::U64
+    }
+}
+
+impl CLTyped for i32 {
+    fn cl_type() -> CLType {
+        CLType::I32
+    }
+}
+
+impl CLTyped for i64 {
+    fn cl_type() -> CLType {
+        CLType::I64
+    }
+}
+
+impl<const N: usize> CLTyped for [u8; N] {
+    fn cl_type() -> CLType {
+        CLType::ByteArray(N as u32)
+    }
+}
+
+impl<T: CLTyped> CLTyped for Option<T> {
+    fn cl_type() -> CLType {
+        CLType::Option(Box::new(T::cl_type()))
+    }
+}
+
+impl<T: CLTyped> CLTyped for Vec<T> {
+    fn cl_type() -> CLType {
+        CLType::List(Box::new(T::cl_type()))
+    }
+}
+
+impl<K: CLTyped, V: CLTyped> CLTyped for BTreeMap<K, V> {
+    fn cl_type() -> CLType {
+        CLType::Map {
+            key: Box::new(K::cl_type()),
+            value: Box::new(V::cl_type()),
+        }
+    }
+}
+
+impl<A: CLTyped, B: CLTyped> CLTyped for (A, B) {
+    fn cl_type() -> CLType {
+        CLType::Tuple(vec![A::cl_type(), B::cl_type()])
+    }
+}
+
+impl<A: CLTyped, B: CLTyped, C: CLTyped> CLTyped for (A, B, C) {
+    fn cl_type() -> CLType {
+        CLType::Tuple(vec![A::cl_type(), B::cl_type(), C::cl_type()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let types = [
+            CLType::Bool,
+            CLType::String,
+            CLType::Unit,
+            CLType::Any,
+            CLType::U8,
+            CLType::U32,
+            CLType::U64,
+            CLType::I32,
+            CLType::I64,
+            CLType::ByteArray(32),
+            <Option<Vec<u8>>>::cl_type(),
+            <BTreeMap<String, (u64, [u8; 32])>>::cl_type(),
+            <(bool, Option<String>, Vec<Vec<i64>>)>::cl_type(),
+            CLType::Tuple(Vec::new()),
+        ];
+        for cl_type in types {
+            assert_eq!(CLType::decode(&cl_type.encode()), Ok(cl_type));
+        }
+    }
+
+    #[test]
+    fn encoding_is_stable() {
+        assert_eq!(CLType::Bool.encode(), [1, 0]);
+        assert_eq!(
+            <Option<[u8; 32]>>::cl_type().encode(),
+            [1, 10, 9, 32, 0, 0, 0]
+        );
+        assert_eq!(<BTreeMap<String, u64>>::cl_type().encode(), [1, 12, 1, 6]);
+        assert_eq!(CLType::Bool.tag(), 0x082f_2207_b4e8_8cc4);
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert_eq!(CLType::decode(&[]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(
+            CLType::decode(&[2, 0]),
+            Err(DecodeError::UnsupportedVersion(2))
+        );
+        assert_eq!(CLType::decode(&[1, 200]), Err(DecodeError::UnknownTag(200)));
+        assert_eq!(CLType::decode(&[1, 11]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(CLType::decode(&[1, 9, 32]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(CLType::decode(&[1, 0, 0]), Err(DecodeError::TrailingBytes));
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth: usize| {
+            (1..depth).fold(CLType::Bool, |inner, _| CLType::Option(Box::new(inner)))
+        };
+        let deepest = nested(MAX_DECODE_DEPTH);
+        assert_eq!(CLType::decode(&deepest.encode()), Ok(deepest));
+        assert_eq!(
+            CLType::decode(&nested(MAX_DECODE_DEPTH + 1).encode()),
+            Err(DecodeError::TooDeep)
+        );
+
+        // Far deeper input fails the same way instead of exhausting the stack.
+        let mut bytes = vec![CL_TYPE_ENCODING_VERSION];
+        bytes.resize(100_000, TAG_OPTION);
+        assert_eq!(CLType::decode(&bytes), Err(DecodeError::TooDeep));
+    }
+}
@@ -1,7 +1,74 @@
-#[derive(Debug)]
+/// Error returned by the host functions.
+///
+/// Every fallible host import returns an `i32` where `0` means success and any other value is one
+/// of the codes below.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Error {
-    Foo,
-    Bar,
+    /// Requested entry does not exist.
+    NotFound,
+    /// Execution ran out of gas.
+    OutOfGas,
+    /// Value exceeds the maximum size accepted by the host.
+    ValueTooLarge,
+    /// Data passed through the host could not be serialized or deserialized.
+    Serialization,
+    /// Called contract reverted its execution.
+    CalleeReverted,
+    /// Contract version has been disabled in its package.
+    ContractDisabled,
+    /// Balance is too low to cover the transfer.
+    InsufficientFunds,
+    /// Signature, or the public key it is checked against, is not valid.
+    InvalidSignature,
+    /// Only the entity that created a contract package can add or disable its versions.
+    PermissionDenied,
+    /// Transfer would raise the balance of its target past `u64::MAX`.
+    BalanceOverflow,
+    /// Code not known to this version of the API.
+    Unknown(i32),
+}
+
+impl Error {
+    pub const fn code(&self) -> i32 {
+        match self {
+            Error::NotFound => 1,
+            Error::OutOfGas => 2,
+            Error::ValueTooLarge => 3,
+            Error::Serialization => 5,
+            Error::CalleeReverted => 6,
+            Error::ContractDisabled => 7,
+            Error::InsufficientFunds => 8,
+            Error::InvalidSignature => 9,
+            Error::PermissionDenied => 10,
+            Error::BalanceOverflow => 11,
+            Error::Unknown(code) => *code,
+        }
+    }
+
+    pub const fn from_code(code: i32) -> Self {
+        match code {
+            1 => Error::NotFound,
+            2 => Error::OutOfGas,
+            3 => Error::ValueTooLarge,
+            5 => Error::Serialization,
+            6 => Error::CalleeReverted,
+            7 => Error::ContractDisabled,
+            8 => Error::InsufficientFunds,
+            9 => Error::InvalidSignature,
+            10 => Error::PermissionDenied,
+            11 => Error::BalanceOverflow,
+            other => Error::Unknown(other),
+        }
+    }
+}
+
+/// Converts a return code of a host import into a result.
+pub fn result_from_code(code: i32) -> Result<(), Error> {
+    if code == 0 {
+        Ok(())
+    } else {
+        Err(Error::from_code(code))
+    }
 }
 
 #[derive(Debug)]
@@ -9,6 +76,58 @@ pub struct Entry {
     pub tag: u64,
 }
 
+/// Address of an account or a contract.
+pub type Address = [u8; 32];
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, BorshSerialize, BorshDeserialize,
+)]
+pub enum Entity {
+    Account(Address),
+    Contract(Address),
+}
+
+impl Default for Entity {
+    fn default() -> Self {
+        Entity::Account([0; 32])
+    }
+}
+
+pub const ENTITY_KIND_ACCOUNT: u32 = 0;
+pub const ENTITY_KIND_CONTRACT: u32 = 1;
+
+/// Entity as written by the host into guest memory.
+#[repr(C)]
+#[derive(Debug)]
+pub struct EntityInfo {
+    /// One of `ENTITY_KIND_*` constants.
+    pub kind: u32,
+    pub address: Address,
+}
+
+impl EntityInfo {
+    pub fn from_entity(entity: Entity) -> Self {
+        match entity {
+            Entity::Account(address) => EntityInfo {
+                kind: ENTITY_KIND_ACCOUNT,
+                address,
+            },
+            Entity::Contract(address) => EntityInfo {
+                kind: ENTITY_KIND_CONTRACT,
+                address,
+            },
+        }
+    }
+
+    pub fn to_entity(&self) -> Result<Entity, Error> {
+        match self.kind {
+            ENTITY_KIND_ACCOUNT => Ok(Entity::Account(self.address)),
+            ENTITY_KIND_CONTRACT => Ok(Entity::Contract(self.address)),
+            _ => Err(Error::Serialization),
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct Slice {
@@ -21,10 +140,21 @@ impl Slice {
         unsafe { slice::from_raw_parts(self.ptr, self.size) }
     }
 }
+#[repr(C)]
+#[derive(Debug)]
 pub struct Param {
     pub name_ptr: *const u8,
     pub name_len: usize,
-    pub ty: u32,
+    /// Type of the parameter encoded with [`crate::CLType::encode`].
+    pub ty_ptr: *const u8,
+    pub ty_len: usize,
+}
+
+impl Param {
+    pub fn cl_type(&self) -> Result<crate::CLType, crate::cl_type::DecodeError> {
+        let bytes = unsafe { slice::from_raw_parts(self.ty_ptr, self.ty_len) };
+        crate::CLType::decode(bytes)
+    }
 }
 
 #[repr(C)]
@@ -36,21 +166,84 @@ pub struct EntryPoint {
     pub params_ptr: *const Param, // pointer of pointers (preferred 'static lifetime)
     pub params_size: usize,
 
-    pub fptr: *const c_void, // extern "C" fn(A1) -> (),
+    /// Entry point function taking no arguments: `extern "C" fn()` in wasm, `fn()` natively.
+    pub fptr: *const c_void,
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// BLAKE2b with a 32 byte digest.
+    Blake2b = 0,
+    Sha256 = 1,
+    Keccak256 = 2,
+}
+
+impl HashAlgorithm {
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(HashAlgorithm::Blake2b),
+            1 => Some(HashAlgorithm::Sha256),
+            2 => Some(HashAlgorithm::Keccak256),
+            _ => None,
+        }
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    /// Ed25519 with a 32 byte public key and a 64 byte signature.
+    Ed25519 = 0,
+    /// ECDSA over secp256k1 and SHA-256 of the message, with a SEC1 encoded public key and a 64
+    /// byte signature.
+    Secp256k1 = 1,
+}
+
+impl SignatureAlgorithm {
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(SignatureAlgorithm::Ed25519),
+            1 => Some(SignatureAlgorithm::Secp256k1),
+            _ => None,
+        }
+    }
+}
+
+pub fn blake2b(data: &[u8]) -> [u8; 32] {
+    hash(HashAlgorithm::Blake2b, data)
+}
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    hash(HashAlgorithm::Sha256, data)
+}
+
+pub fn keccak256(data: &[u8]) -> [u8; 32] {
+    hash(HashAlgorithm::Keccak256, data)
+}
+
+/// Contract added to a package by [`add_contract_version`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContractVersion {
+    /// Address of this particular version.
+    pub address: Address,
+    /// Version number within the package, starting from 1.
+    pub version: u32,
 }
 
 #[cfg(target_arch = "wasm32")]
 mod wasm {
-    use bytes::Bytes;
-
     use std::{
-        alloc::{self, Layout},
         ffi::c_void,
-        mem::{self, MaybeUninit},
+        mem,
         ptr::{self, NonNull},
     };
 
-    use super::{Entry, Error};
+    use super::{
+        result_from_code, Address, ContractVersion, Entity, EntityInfo, Entry, EntryPoint, Error,
+        HashAlgorithm, SignatureAlgorithm,
+    };
 
     #[derive(Debug)]
     #[repr(C)]
@@ -81,8 +274,82 @@ mod wasm {
         ) -> i32;
         pub fn casper_print(msg_ptr: *const u8, msg_size: usize) -> i32;
         pub fn casper_revert(code: u32);
+        pub fn casper_return(data_ptr: *const u8, data_size: usize);
+        pub fn casper_copy_input(
+            alloc: extern "C" fn(usize, *mut c_void) -> *const u8,
+            alloc_ctx: *const c_void,
+        ) -> i32;
+        pub fn casper_env_caller(info: *mut EntityInfo) -> i32;
+        pub fn casper_env_initiator(address_ptr: *mut u8) -> i32;
+        /// Returns `NotFound` if the code is not executed as a contract.
+        pub fn casper_env_contract_address(address_ptr: *mut u8) -> i32;
+        pub fn casper_env_block_time() -> u64;
+        pub fn casper_env_block_height() -> u64;
+        pub fn casper_env_attached_value() -> u64;
+        /// Calls `entry_point` of the contract at `address` with a Borsh-encoded named arguments
+        /// map as input, attaching `value` tokens. Data returned by the callee is copied through
+        /// `alloc`.
+        pub fn casper_call(
+            address_ptr: *const u8,
+            address_size: usize,
+            entry_point_ptr: *const u8,
+            entry_point_size: usize,
+            input_ptr: *const u8,
+            input_size: usize,
+            value: u64,
+            alloc: extern "C" fn(usize, *mut c_void) -> *const u8,
+            alloc_ctx: *const c_void,
+        ) -> i32;
+        /// Creates a contract with given entry points and writes its address into `address_ptr`.
+        pub fn casper_create_contract(
+            entry_points_ptr: *const EntryPoint,
+            entry_points_size: usize,
+            address_ptr: *mut u8,
+        ) -> i32;
 
-        // pub fn casper_add_contract_version(hash_ptr: *const u8, hash_len: usize, entry_points);
+        pub fn casper_create_contract_package(address_ptr: *mut u8) -> i32;
+        /// Adds a new version of the contract package and makes it the one called through the
+        /// package address. Only the creator of the package may add versions or disable them.
+        pub fn casper_add_contract_version(
+            package_ptr: *const u8,
+            package_size: usize,
+            entry_points_ptr: *const EntryPoint,
+            entry_points_size: usize,
+            version: *mut ContractVersion,
+        ) -> i32;
+        pub fn casper_disable_contract_version(
+            package_ptr: *const u8,
+            package_size: usize,
+            version: u32,
+        ) -> i32;
+        /// Transfers `amount` from the currently executing entity.
+        pub fn casper_transfer_to_account(
+            address_ptr: *const u8,
+            address_size: usize,
+            amount: u64,
+        ) -> i32;
+        pub fn casper_transfer_to_contract(
+            address_ptr: *const u8,
+            address_size: usize,
+            amount: u64,
+        ) -> i32;
+        pub fn casper_balance(entity: *const EntityInfo, balance: *mut u64) -> i32;
+        /// Writes the 32 byte digest of the data into `digest_ptr`.
+        pub fn casper_hash(
+            algorithm: u32,
+            data_ptr: *const u8,
+            data_size: usize,
+            digest_ptr: *mut u8,
+        ) -> i32;
+        pub fn casper_verify_signature(
+            algorithm: u32,
+            message_ptr: *const u8,
+            message_size: usize,
+            signature_ptr: *const u8,
+            signature_size: usize,
+            public_key_ptr: *const u8,
+            public_key_size: usize,
+        ) -> i32;
         // pub fn foo(slice: *const Slice);
     }
 
@@ -95,20 +362,59 @@ mod wasm {
         unreachable!()
     }
 
-    pub fn read_into<'a>(
-        key_space: u64,
-        key: &[u8],
-        destination: &'a mut [u8],
-    ) -> Option<&'a [u8]> {
-        let mut what_size: Option<usize> = None;
-        read(key_space, key, |size| {
-            what_size = Some(size);
-            NonNull::new(destination.as_mut_ptr()).unwrap()
-        });
+    pub fn ret(data: &[u8]) {
+        unsafe { casper_return(data.as_ptr(), data.len()) };
+    }
+
+    pub fn caller() -> Entity {
+        let mut info = EntityInfo {
+            kind: 0,
+            address: [0; 32],
+        };
+        let ret = unsafe { casper_env_caller(&mut info) };
+        result_from_code(ret)
+            .and_then(|()| info.to_entity())
+            .expect("host should provide caller")
+    }
+
+    pub fn initiator() -> Address {
+        let mut address = [0; 32];
+        let ret = unsafe { casper_env_initiator(address.as_mut_ptr()) };
+        result_from_code(ret).expect("host should provide initiator");
+        address
+    }
+
+    pub fn contract_address() -> Option<Address> {
+        let mut address = [0; 32];
+        let ret = unsafe { casper_env_contract_address(address.as_mut_ptr()) };
+        match result_from_code(ret) {
+            Ok(()) => Some(address),
+            Err(Error::NotFound) => None,
+            Err(error) => panic!("host failed to provide contract address: {error:?}"),
+        }
+    }
 
-        let size = what_size?;
+    pub fn block_time() -> u64 {
+        unsafe { casper_env_block_time() }
+    }
+
+    pub fn block_height() -> u64 {
+        unsafe { casper_env_block_height() }
+    }
 
-        Some(&destination[..size])
+    pub fn attached_value() -> u64 {
+        unsafe { casper_env_attached_value() }
+    }
+
+    /// Passed to the host along with a pointer to `Option<F>`; the host calls it once with the size of
+    /// the data it is about to copy and writes into the returned buffer.
+    extern "C" fn alloc_cb<F: FnOnce(usize) -> NonNull<u8>>(
+        len: usize,
+        ctx: *mut c_void,
+    ) -> *const u8 {
+        let opt_closure = ctx as *mut Option<F>;
+        let mut ptr = unsafe { (*opt_closure).take().unwrap()(len) };
+        unsafe { ptr.as_mut() }
     }
 
     pub fn read<F: FnOnce(usize) -> NonNull<u8>>(
@@ -123,16 +429,8 @@ mod wasm {
             tag: 0,
         };
 
-        extern "C" fn alloc_cb<F: FnOnce(usize) -> NonNull<u8>>(
-            len: usize,
-            ctx: *mut c_void,
-        ) -> *const u8 {
-            let opt_closure = ctx as *mut Option<F>;
-            let mut ptr = unsafe { (*opt_closure).take().unwrap()(len) };
-            unsafe { ptr.as_mut() }
-        }
-
-        let ctx = &Some(f) as *const _ as *mut c_void;
+        let mut f = Some(f);
+        let ctx = &mut f as *mut Option<F> as *mut c_void;
 
         let ret = unsafe {
             casper_read(
@@ -145,17 +443,146 @@ mod wasm {
             )
         };
 
-        if ret == 0 {
-            Ok(Some(Entry { tag: info.tag }))
-        } else if ret == 1 {
-            Ok(None)
-        } else {
-            Err(Error::Foo)
+        match result_from_code(ret) {
+            Ok(()) => Ok(Some(Entry { tag: info.tag })),
+            Err(Error::NotFound) => Ok(None),
+            Err(error) => Err(error),
         }
     }
 
+    pub fn copy_input<F: FnOnce(usize) -> NonNull<u8>>(f: F) -> Result<(), Error> {
+        let mut f = Some(f);
+        let ctx = &mut f as *mut Option<F> as *mut c_void;
+        let ret = unsafe { casper_copy_input(alloc_cb::<F>, ctx) };
+        result_from_code(ret)
+    }
+
+    pub fn call<F: FnOnce(usize) -> NonNull<u8>>(
+        address: &Address,
+        entry_point: &str,
+        input: &[u8],
+        value: u64,
+        f: F,
+    ) -> Result<(), Error> {
+        let mut f = Some(f);
+        let ctx = &mut f as *mut Option<F> as *mut c_void;
+        let ret = unsafe {
+            casper_call(
+                address.as_ptr(),
+                address.len(),
+                entry_point.as_ptr(),
+                entry_point.len(),
+                input.as_ptr(),
+                input.len(),
+                value,
+                alloc_cb::<F>,
+                ctx,
+            )
+        };
+        result_from_code(ret)
+    }
+
+    pub fn create_contract(entry_points: &[EntryPoint]) -> Result<Address, Error> {
+        let mut address = [0; 32];
+        let ret = unsafe {
+            casper_create_contract(
+                entry_points.as_ptr(),
+                entry_points.len(),
+                address.as_mut_ptr(),
+            )
+        };
+        result_from_code(ret)?;
+        Ok(address)
+    }
+
+    pub fn create_contract_package() -> Result<Address, Error> {
+        let mut address = [0; 32];
+        let ret = unsafe { casper_create_contract_package(address.as_mut_ptr()) };
+        result_from_code(ret)?;
+        Ok(address)
+    }
+
+    pub fn add_contract_version(
+        package: &Address,
+        entry_points: &[EntryPoint],
+    ) -> Result<ContractVersion, Error> {
+        let mut version = ContractVersion {
+            address: [0; 32],
+            version: 0,
+        };
+        let ret = unsafe {
+            casper_add_contract_version(
+                package.as_ptr(),
+                package.len(),
+                entry_points.as_ptr(),
+                entry_points.len(),
+                &mut version,
+            )
+        };
+        result_from_code(ret)?;
+        Ok(version)
+    }
+
+    pub fn disable_contract_version(package: &Address, version: u32) -> Result<(), Error> {
+        let ret =
+            unsafe { casper_disable_contract_version(package.as_ptr(), package.len(), version) };
+        result_from_code(ret)
+    }
+
+    pub fn transfer_to_account(address: &Address, amount: u64) -> Result<(), Error> {
+        let ret = unsafe { casper_transfer_to_account(address.as_ptr(), address.len(), amount) };
+        result_from_code(ret)
+    }
+
+    pub fn transfer_to_contract(address: &Address, amount: u64) -> Result<(), Error> {
+        let ret = unsafe { casper_transfer_to_contract(address.as_ptr(), address.len(), amount) };
+        result_from_code(ret)
+    }
+
+    pub fn balance(entity: Entity) -> u64 {
+        let info = EntityInfo::from_entity(entity);
+        let mut balance = 0;
+        let ret = unsafe { casper_balance(&info, &mut balance) };
+        result_from_code(ret).expect("host should provide balance");
+        balance
+    }
+
+    pub fn hash(algorithm: HashAlgorithm, data: &[u8]) -> [u8; 32] {
+        let mut digest = [0; 32];
+        let ret = unsafe {
+            casper_hash(
+                algorithm as u32,
+                data.as_ptr(),
+                data.len(),
+                digest.as_mut_ptr(),
+            )
+        };
+        result_from_code(ret).expect("host should hash data");
+        digest
+    }
+
+    pub fn verify_signature(
+        algorithm: SignatureAlgorithm,
+        message: &[u8],
+        signature: &[u8],
+        public_key: &[u8],
+    ) -> Result<(), Error> {
+        let ret = unsafe {
+            casper_verify_signature(
+                algorithm as u32,
+                message.as_ptr(),
+                message.len(),
+                signature.as_ptr(),
+                signature.len(),
+                public_key.as_ptr(),
+                public_key.len(),
+            )
+        };
+        result_from_code(ret)
+    }
+
     pub fn write(key_space: u64, key: &[u8], value_tag: u64, value: &[u8]) -> Result<(), Error> {
-        let _ret = unsafe {
+        let ret = unsafe {
             casper_write(
                 key_space,
                 key.as_ptr(),
@@ -165,7 +592,7 @@ mod wasm {
                 value.len(),
             )
         };
-        Ok(())
+        result_from_code(ret)
     }
 
     #[no_mangle]
@@ -188,99 +615,496 @@ mod wasm {
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-mod native {
-    use std::{
-        borrow::{Borrow, BorrowMut},
-        cell::RefCell,
-        collections::BTreeMap,
-    };
+mod native;
 
-    use bytes::Bytes;
+use core::slice;
+use std::ffi::c_void;
 
-    use super::{Entry, Error};
+use borsh::{BorshDeserialize, BorshSerialize};
 
-    #[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
-    struct TaggedValue {
-        tag: u64,
-        value: Bytes,
+#[cfg(not(target_arch = "wasm32"))]
+pub use native::{
+    add_contract_version, attached_value, balance, block_height, block_time, call, caller, context,
+    contract_address, copy_input, cost_table, create_contract, create_contract_package,
+    disable_contract_version, gas_used, hash, initiator, print, read, register_contract, reset_gas,
+    ret, revert, set_balance, set_context, set_cost_table, set_gas_limit, take_gas_reports,
+    take_return_data, transfer_to_account, transfer_to_contract, verify_signature, write, Context,
+    Cost, CostTable, GasReport, StateFormat, StorageEntry, TestEnv, MAX_VALUE_SIZE,
+};
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use native::{register_function, registered_function, transaction};
+#[cfg(target_arch = "wasm32")]
+pub use wasm::{
+    add_contract_version, attached_value, balance, block_height, block_time, call, caller,
+    contract_address, copy_input, create_contract, create_contract_package,
+    disable_contract_version, hash, initiator, print, read, ret, revert, transfer_to_account,
+    transfer_to_contract, verify_signature, write,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{runtime_args, CLType, RuntimeArgs};
+
+    #[test]
+    fn error_codes_round_trip() {
+        for error in [
+            Error::NotFound,
+            Error::OutOfGas,
+            Error::ValueTooLarge,
+            Error::Serialization,
+            Error::CalleeReverted,
+            Error::ContractDisabled,
+            Error::InsufficientFunds,
+            Error::InvalidSignature,
+            Error::PermissionDenied,
+            Error::BalanceOverflow,
+            Error::Unknown(1234),
+        ] {
+            assert_eq!(Error::from_code(error.code()), error);
+        }
+        assert_eq!(result_from_code(0), Ok(()));
+        assert_eq!(result_from_code(3), Err(Error::ValueTooLarge));
+        assert_eq!(Error::from_code(4), Error::Unknown(4));
     }
 
-    #[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
-    struct BorrowedTaggedValue<'a> {
-        tag: u64,
-        value: &'a [u8],
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn only_the_package_owner_manages_versions() {
+        let owner = Entity::Account([1; 32]);
+        let context = |caller| Context {
+            caller,
+            ..Default::default()
+        };
+        set_context(context(owner));
+        let package = create_contract_package().unwrap();
+        let v1 = add_contract_version(&package, &[]).unwrap();
+
+        set_context(context(Entity::Account([2; 32])));
+        assert!(matches!(
+            add_contract_version(&package, &[]),
+            Err(Error::PermissionDenied)
+        ));
+        assert_eq!(
+            disable_contract_version(&package, v1.version),
+            Err(Error::PermissionDenied)
+        );
+
+        set_context(context(owner));
+        assert_eq!(disable_contract_version(&package, v1.version), Ok(()));
     }
-    type Container = BTreeMap<u64, BTreeMap<Bytes, TaggedValue>>;
 
-    #[derive(Default, Clone)]
-    pub(crate) struct LocalKV {
-        db: Container,
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn write_rejects_large_values() {
+        let value = vec![0u8; MAX_VALUE_SIZE + 1];
+        assert_eq!(write(0, b"key", 0, &value), Err(Error::ValueTooLarge));
     }
 
-    // impl LocalKV {
-    //     pub(crate) fn update(&mut self, db: LocalKV) {
-    //         self.db = db.db
-    //     }
-    // }
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn ret_captures_return_data() {
+        assert_eq!(take_return_data(), None);
+        ret(b"result");
+        assert_eq!(take_return_data().as_deref(), Some(&b"result"[..]));
+        assert_eq!(take_return_data(), None);
+    }
 
-    thread_local! {
-        static DB: RefCell<LocalKV> = RefCell::new(LocalKV::default());
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn context_can_be_set_by_tests() {
+        assert_eq!(caller(), Entity::Account([0; 32]));
+        assert_eq!(contract_address(), None);
+        set_context(Context {
+            caller: Entity::Contract([1; 32]),
+            initiator: [2; 32],
+            contract_address: Some([3; 32]),
+            block_time: 1_000,
+            block_height: 10,
+            attached_value: 0,
+        });
+        assert_eq!(caller(), Entity::Contract([1; 32]));
+        assert_eq!(initiator(), [2; 32]);
+        assert_eq!(contract_address(), Some([3; 32]));
+        assert_eq!(block_time(), 1_000);
+        assert_eq!(block_height(), 10);
     }
 
-    pub fn print(msg: &str) {
-        println!("💻 {msg}");
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn call_dispatches_into_registered_contract() {
+        fn increment() {
+            let value: u64 = crate::get_named_arg("value").unwrap();
+            write(0, b"value", 0, &value.to_le_bytes()).unwrap();
+            crate::ret(&(value + 1)).unwrap();
+        }
+        fn whoami() {
+            crate::ret(&(caller(), contract_address())).unwrap();
+        }
+        fn fail() {
+            revert(1);
+        }
+        const CONTRACT: Address = [1; 32];
+        register_contract(
+            CONTRACT,
+            &[("increment", increment), ("whoami", whoami), ("fail", fail)],
+        );
+
+        let args = runtime_args! { "value" => 41u64 };
+        let result: u64 = crate::call_contract(CONTRACT, "increment", args).unwrap();
+        assert_eq!(result, 42);
+
+        // Storage written by the contract is not visible outside of it.
+        assert!(read(0, b"value", |_| unreachable!()).unwrap().is_none());
+
+        let (caller, address): (Entity, Option<Address>) =
+            crate::call_contract(CONTRACT, "whoami", RuntimeArgs::new()).unwrap();
+        assert_eq!(caller, Entity::Account([0; 32]));
+        assert_eq!(address, Some(CONTRACT));
+        assert_eq!(contract_address(), None);
+
+        assert!(matches!(
+            crate::call_contract::<()>(CONTRACT, "fail", RuntimeArgs::new()),
+            Err(crate::ApiError::Host(Error::CalleeReverted))
+        ));
+        assert!(matches!(
+            crate::call_contract::<()>(CONTRACT, "missing", RuntimeArgs::new()),
+            Err(crate::ApiError::Host(Error::NotFound))
+        ));
     }
-    pub fn write(key_space: u64, key: &[u8], value_tag: u64, value: &[u8]) -> Result<(), Error> {
-        DB.with(|db| {
-            // db.borrow_mut().db.insert(key_space, value: Bytes::copy_from_slice(key) }, TaggedValue { tag: value_tag, value: Bytes::copy_from_slice(value) })
-            db.borrow_mut().db.entry(key_space).or_default().insert(
-                Bytes::copy_from_slice(key),
-                TaggedValue {
-                    tag: value_tag,
-                    value: Bytes::copy_from_slice(value),
-                },
-            );
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn transfers_move_balances() {
+        fn withdraw() {
+            let amount: u64 = crate::get_named_arg("amount").unwrap();
+            let Entity::Account(account) = caller() else {
+                revert(1)
+            };
+            transfer_to_account(&account, amount).unwrap();
+        }
+        const ACCOUNT: Address = [1; 32];
+        const CONTRACT: Address = [2; 32];
+        register_contract(CONTRACT, &[("withdraw", withdraw)]);
+        set_context(Context {
+            caller: Entity::Account(ACCOUNT),
+            ..Default::default()
         });
-        Ok(())
+        set_balance(Entity::Account(ACCOUNT), 100);
+
+        transfer_to_contract(&CONTRACT, 60).unwrap();
+        assert_eq!(balance(Entity::Account(ACCOUNT)), 40);
+        assert_eq!(balance(Entity::Contract(CONTRACT)), 60);
+
+        assert_eq!(
+            transfer_to_account(&[3; 32], 41),
+            Err(Error::InsufficientFunds)
+        );
+        transfer_to_account(&[3; 32], 40).unwrap();
+        assert_eq!(balance(Entity::Account([3; 32])), 40);
+        assert_eq!(transfer_to_contract(&[4; 32], 0), Err(Error::NotFound));
+
+        let args = runtime_args! { "amount" => 10u64 };
+        crate::call_contract::<()>(CONTRACT, "withdraw", args).unwrap();
+        assert_eq!(balance(Entity::Account(ACCOUNT)), 10);
+        assert_eq!(balance(Entity::Contract(CONTRACT)), 50);
+
+        let args = runtime_args! { "amount" => 51u64 };
+        assert!(crate::call_contract::<()>(CONTRACT, "withdraw", args).is_err());
+        assert_eq!(balance(Entity::Contract(CONTRACT)), 50);
+
+        // Overflowing the target leaves both balances as they were.
+        set_balance(Entity::Account([3; 32]), u64::MAX);
+        assert_eq!(
+            transfer_to_account(&[3; 32], 1),
+            Err(Error::BalanceOverflow)
+        );
+        assert_eq!(balance(Entity::Account(ACCOUNT)), 10);
+        assert_eq!(balance(Entity::Account([3; 32])), u64::MAX);
+        // Transfers to oneself only need the funds to be there.
+        transfer_to_account(&ACCOUNT, 10).unwrap();
+        assert_eq!(balance(Entity::Account(ACCOUNT)), 10);
     }
-    pub fn read(
-        key_space: u64,
-        key: &[u8],
-        func: impl FnOnce(usize) -> core::ptr::NonNull<u8>,
-    ) -> Result<Option<Entry>, Error> {
-        let value = DB.with(|db| db.borrow().db.get(&key_space)?.get(key).cloned());
-        match value {
-            Some(tagged_value) => Ok(Some(Entry {
-                tag: tagged_value.tag,
-            })),
-            None => Ok(None),
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn reverted_calls_roll_back_state() {
+        fn stored(key: &[u8]) -> bool {
+            let mut value = Vec::new();
+            read(0, key, |size| crate::reserve_vec_space(&mut value, size))
+                .unwrap()
+                .is_some()
+        }
+        fn store_and_fail() {
+            write(0, b"inner", 0, b"value").unwrap();
+            transfer_to_account(&ACCOUNT, 10).unwrap();
+            create_contract_package().unwrap();
+            revert(1);
         }
+        fn store_and_call() {
+            write(0, b"outer", 0, b"value").unwrap();
+            assert!(matches!(
+                crate::call_contract::<()>(CONTRACT, "store_and_fail", RuntimeArgs::new()),
+                Err(crate::ApiError::Host(Error::CalleeReverted))
+            ));
+            crate::ret(&(stored(b"outer"), stored(b"inner"))).unwrap();
+        }
+        const ACCOUNT: Address = [1; 32];
+        const CONTRACT: Address = [2; 32];
+        register_contract(
+            CONTRACT,
+            &[
+                ("store_and_fail", store_and_fail),
+                ("store_and_call", store_and_call),
+            ],
+        );
+        set_balance(Entity::Contract(CONTRACT), 50);
+
+        // Only the writes of the reverted inner call are discarded.
+        let stored: (bool, bool) =
+            crate::call_contract(CONTRACT, "store_and_call", RuntimeArgs::new()).unwrap();
+        assert_eq!(stored, (true, false));
+        assert_eq!(balance(Entity::Account(ACCOUNT)), 0);
+        assert_eq!(balance(Entity::Contract(CONTRACT)), 50);
+
+        // The package created by the reverted call is gone, and its address is handed out again.
+        let mut address = [0; 32];
+        address[7] = 1;
+        assert_eq!(create_contract_package(), Ok(address));
+
+        // Entry points run through the test helpers roll back the same way.
+        let env = TestEnv::current();
+        let result = crate::testing::call(|| {
+            write(0, b"outside", 0, b"value").unwrap();
+            set_balance(Entity::Account(ACCOUNT), 10);
+            revert(2)
+        });
+        assert_eq!(result, Err(crate::testing::Revert::Code(2)));
+        assert_eq!(env.read(None, 0, b"outside"), None);
+        assert_eq!(balance(Entity::Account(ACCOUNT)), 0);
     }
 
-    // pub fn dispatch<Args, R>(export: impl Fn(Args) -> R, args: Args) -> R {
-    //     export(args)
-    // }
-    pub fn revert(code: u32) -> ! {
-        panic!("revert with code {code}")
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn concurrent_calls_keep_their_frames() {
+        fn slow_store() {
+            let key: String = crate::get_named_arg("key").unwrap();
+            write(0, key.as_bytes(), 0, b"value").unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            // Still the arguments of this call, even if another thread called in the meantime.
+            let key_after: String = crate::get_named_arg("key").unwrap();
+            if key == "revert" {
+                revert(1);
+            }
+            crate::ret(&key_after).unwrap();
+        }
+        const CONTRACT: Address = [2; 32];
+        let env = TestEnv::new();
+        env.run(|| register_contract(CONTRACT, &[("slow_store", slow_store)]));
+
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(2));
+        let threads: Vec<_> = ["revert", "keep"]
+            .into_iter()
+            .map(|key| {
+                let (env, barrier) = (env.clone(), barrier.clone());
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    env.call::<String>(
+                        CONTRACT,
+                        "slow_store",
+                        runtime_args! { "key" => key.to_string() },
+                    )
+                })
+            })
+            .collect();
+        let results: Vec<_> = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+
+        assert!(matches!(
+            results[0],
+            Err(crate::ApiError::Host(Error::CalleeReverted))
+        ));
+        assert_eq!(results[1].as_ref().unwrap(), "keep");
+        // The revert only discarded the writes of its own call.
+        assert_eq!(env.read(Some(CONTRACT), 0, b"revert"), None);
+        assert!(env.read(Some(CONTRACT), 0, b"keep").is_some());
     }
-}
 
-use core::slice;
-use std::ffi::c_void;
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn gas_is_metered_per_call() {
+        fn store() {
+            write(0, b"key", 0, b"value").unwrap();
+        }
+        fn store_twice() {
+            write(0, b"key", 0, b"value").unwrap();
+            crate::call_contract::<()>(CONTRACT, "store", RuntimeArgs::new()).unwrap();
+        }
+        const CONTRACT: Address = [1; 32];
+        register_contract(CONTRACT, &[("store", store), ("store_twice", store_twice)]);
+        set_cost_table(CostTable {
+            write: Cost::new(100, 1),
+            call: Cost::new(10, 0),
+            copy_input: Cost::default(),
+            ..cost_table()
+        });
 
-use bytes::Bytes;
-#[cfg(not(target_arch = "wasm32"))]
-pub use native::{print, read, revert, write};
-#[cfg(target_arch = "wasm32")]
-pub use wasm::{print, read, revert, write};
+        crate::call_contract::<()>(CONTRACT, "store", RuntimeArgs::new()).unwrap();
+        assert_eq!(gas_used(), 10 + 108);
+        let reports = take_gas_reports();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].entry_point, "store");
+        assert_eq!(reports[0].gas_used, 108);
+        assert_eq!(reports[0].host_functions["write"], 108);
+
+        reset_gas();
+        crate::call_contract::<()>(CONTRACT, "store_twice", RuntimeArgs::new()).unwrap();
+        let reports = take_gas_reports();
+        assert_eq!(reports[0].entry_point, "store");
+        assert_eq!(reports[1].entry_point, "store_twice");
+        assert_eq!(reports[1].gas_used, 108 + 10 + 108);
+        assert_eq!(reports[1].host_functions["write"], 216);
+
+        // Running out of gas in a nested call reverts the whole execution, and the outermost call
+        // reports it.
+        reset_gas();
+        set_gas_limit(Some(200));
+        assert!(matches!(
+            crate::call_contract::<()>(CONTRACT, "store_twice", RuntimeArgs::new()),
+            Err(crate::ApiError::Host(Error::OutOfGas))
+        ));
+        assert_eq!(gas_used(), 200);
+
+        // Outside of calls, running out of gas unwinds like a revert.
+        let result = crate::testing::call(|| write(0, b"key", 0, b"value"));
+        assert_eq!(result.unwrap_err(), crate::testing::Revert::OutOfGas);
+    }
+
+    #[test]
+    fn hashes() {
+        assert_eq!(
+            sha256(b"abc"),
+            hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        assert_eq!(
+            keccak256(b""),
+            hex("c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470")
+        );
+        assert_eq!(
+            blake2b(b""),
+            hex("0e5751c026e543b2e8ab2eb06099daa1d1e5df47778f7787faab45cdf12fe3a8")
+        );
+    }
+
+    fn hex(digest: &str) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&digest[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        bytes
+    }
 
-// #[cfg(test)]
-// mod tests {
-//     use std::mem;
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn verifies_signatures() {
+        let message = b"message";
 
-//     use super::*;
-//     fn test_fptr(param_1: *const Slice, param_2: *const Slice) {
+        let signing_key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        let signature = ed25519_dalek::Signer::sign(&signing_key, message).to_bytes();
+        let public_key = signing_key.verifying_key().to_bytes();
+        let algorithm = SignatureAlgorithm::Ed25519;
+        assert_eq!(
+            verify_signature(algorithm, message, &signature, &public_key),
+            Ok(())
+        );
+        assert_eq!(
+            verify_signature(algorithm, b"other", &signature, &public_key),
+            Err(Error::InvalidSignature)
+        );
+        assert_eq!(
+            verify_signature(algorithm, message, &signature[1..], &public_key),
+            Err(Error::InvalidSignature)
+        );
+
+        let signing_key = k256::ecdsa::SigningKey::from_bytes(&[7; 32].into()).unwrap();
+        let signature: k256::ecdsa::Signature =
+            k256::ecdsa::signature::Signer::sign(&signing_key, message);
+        let public_key = signing_key.verifying_key().to_sec1_bytes();
+        let algorithm = SignatureAlgorithm::Secp256k1;
+        assert_eq!(
+            verify_signature(algorithm, message, &signature.to_bytes(), &public_key),
+            Ok(())
+        );
+        assert_eq!(
+            verify_signature(algorithm, b"other", &signature.to_bytes(), &public_key),
+            Err(Error::InvalidSignature)
+        );
+        assert_eq!(
+            verify_signature(algorithm, message, &signature.to_bytes(), &public_key[1..]),
+            Err(Error::InvalidSignature)
+        );
+    }
 
-//     }
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn named_args_come_from_input() {
+        assert!(crate::named_args().unwrap().is_empty());
+        TestEnv::current().set_args(runtime_args! { "flag" => true });
+        assert!(crate::get_named_arg::<bool>("flag").unwrap());
+        assert!(matches!(
+            crate::get_named_arg::<bool>("missing"),
+            Err(crate::ApiError::MissingArgument)
+        ));
+
+        TestEnv::current().set_args(runtime_args! { "flag" => "true".to_string() });
+        assert!(matches!(
+            crate::get_named_arg::<bool>("flag"),
+            Err(crate::ApiError::InvalidArgumentType {
+                name,
+                expected: CLType::Bool,
+                got: CLType::String,
+            }) if name == "flag"
+        ));
+
+        let mut args = RuntimeArgs::new();
+        args.insert_encoded("flag", CLType::Bool, vec![1, 0]);
+        TestEnv::current().set_args(args);
+        assert!(matches!(
+            crate::get_named_arg::<bool>("flag"),
+            Err(crate::ApiError::Io(_))
+        ));
+    }
 
-//     }
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn state_files_round_trip() {
+        let env = TestEnv::new();
+        env.write(None, 0, b"greeting", 1, b"hello");
+        env.write(Some([7; 32]), 2, b"counter", 3, &5u64.to_le_bytes());
+        env.set_context(Context {
+            contract_address: Some([7; 32]),
+            ..env.context()
+        });
+
+        let dir = std::env::temp_dir();
+        for (name, format) in [
+            ("state.json", StateFormat::Json),
+            ("state.borsh", StateFormat::Borsh),
+        ] {
+            let path = dir.join(format!("{}-{name}", std::process::id()));
+            assert_eq!(StateFormat::from_path(&path), format);
+            env.save(&path, format).unwrap();
+            let loaded = TestEnv::load(&path, format).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(loaded.storage(), env.storage());
+            assert_eq!(loaded.context().contract_address, Some([7; 32]));
+        }
+
+        let json = serde_json::to_value(&env.storage()[0]).unwrap();
+        assert_eq!(json["contract"], serde_json::Value::Null);
+        assert_eq!(json["key"], "6772656574696e67");
+        assert_eq!(json["value"], "68656c6c6f");
+    }
+}
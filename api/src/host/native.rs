@@ -0,0 +1,572 @@
+use std::{collections::BTreeMap, ffi::c_void, mem, panic, ptr::NonNull, slice, str};
+
+use borsh::BorshDeserialize;
+use bytes::Bytes;
+
+use crate::{testing::Revert, RuntimeArgs};
+
+use super::{
+    Address, ContractVersion, Entity, Entry, EntryPoint, Error, HashAlgorithm, SignatureAlgorithm,
+};
+
+mod env;
+mod gas;
+mod journal;
+
+use env::{lock_calls, with_state, State};
+
+pub(crate) use journal::transaction;
+
+pub use env::{StateFormat, StorageEntry, TestEnv};
+pub use gas::{
+    cost_table, gas_used, reset_gas, set_cost_table, set_gas_limit, take_gas_reports, Cost,
+    CostTable, GasReport,
+};
+
+/// Largest value accepted by [`write`].
+pub const MAX_VALUE_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
+struct TaggedValue {
+    tag: u64,
+    value: Bytes,
+}
+type Container = BTreeMap<u64, BTreeMap<Bytes, TaggedValue>>;
+
+#[derive(Default, Clone)]
+struct LocalKV {
+    /// Storage of every contract, keyed by its address. Code executed outside of a contract
+    /// uses the `None` entry.
+    db: BTreeMap<Option<Address>, Container>,
+}
+
+/// Contract callable through [`call`].
+#[derive(Clone)]
+struct NativeContract {
+    entry_points: BTreeMap<String, fn()>,
+    /// Package this contract is a version of. Versions execute under the package address so
+    /// their storage survives upgrades.
+    package: Option<Address>,
+}
+
+#[derive(Clone)]
+struct PackageVersion {
+    address: Address,
+    enabled: bool,
+}
+
+#[derive(Default, Clone)]
+struct ContractPackage {
+    /// Entity that created the package, the only one allowed to manage its versions.
+    owner: Entity,
+    /// Versions in the order they were added; version `n` is at index `n - 1`.
+    versions: Vec<PackageVersion>,
+}
+
+impl LocalKV {
+    fn insert(
+        &mut self,
+        contract: Option<Address>,
+        key_space: u64,
+        key: &[u8],
+        tag: u64,
+        value: &[u8],
+    ) -> Option<TaggedValue> {
+        self.db
+            .entry(contract)
+            .or_default()
+            .entry(key_space)
+            .or_default()
+            .insert(
+                Bytes::copy_from_slice(key),
+                TaggedValue {
+                    tag,
+                    value: Bytes::copy_from_slice(value),
+                },
+            )
+    }
+}
+
+/// Execution context reported by the native host.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Context {
+    pub caller: Entity,
+    pub initiator: Address,
+    pub contract_address: Option<Address>,
+    pub block_time: u64,
+    pub block_height: u64,
+    /// Tokens transferred to the contract along with the current call.
+    pub attached_value: u64,
+}
+
+/// Sets the execution context seen by subsequent host calls in the current [`TestEnv`].
+pub fn set_context(context: Context) {
+    with_state(|state| state.context = context);
+}
+
+pub fn context() -> Context {
+    with_state(|state| state.context.clone())
+}
+
+pub fn caller() -> Entity {
+    gas::charge("caller", |costs| costs.env, 0);
+    with_state(|state| state.context.caller)
+}
+
+pub fn initiator() -> Address {
+    gas::charge("initiator", |costs| costs.env, 0);
+    with_state(|state| state.context.initiator)
+}
+
+pub fn contract_address() -> Option<Address> {
+    gas::charge("contract_address", |costs| costs.env, 0);
+    with_state(|state| state.context.contract_address)
+}
+
+pub fn block_time() -> u64 {
+    gas::charge("block_time", |costs| costs.env, 0);
+    with_state(|state| state.context.block_time)
+}
+
+pub fn block_height() -> u64 {
+    gas::charge("block_height", |costs| costs.env, 0);
+    with_state(|state| state.context.block_height)
+}
+
+pub fn attached_value() -> u64 {
+    gas::charge("attached_value", |costs| costs.env, 0);
+    with_state(|state| state.context.attached_value)
+}
+
+pub fn print(msg: &str) {
+    gas::charge("print", |costs| costs.print, msg.len());
+    eprintln!("💻 {msg}");
+    with_state(|state| state.printed.push(msg.to_string()));
+}
+pub fn write(key_space: u64, key: &[u8], value_tag: u64, value: &[u8]) -> Result<(), Error> {
+    gas::charge("write", |costs| costs.write, key.len() + value.len());
+    if value.len() > MAX_VALUE_SIZE {
+        return Err(Error::ValueTooLarge);
+    }
+    with_state(|state| {
+        let contract_address = state.context.contract_address;
+        state.insert_value(contract_address, key_space, key, value_tag, value);
+    });
+    Ok(())
+}
+/// Copies the named arguments of the current call as a Borsh-encoded map.
+pub fn copy_input(func: impl FnOnce(usize) -> NonNull<u8>) -> Result<(), Error> {
+    let input = with_state(|state| borsh::to_vec(&state.args)).map_err(|_| Error::Serialization)?;
+    gas::charge("copy_input", |costs| costs.copy_input, input.len());
+    let ptr = func(input.len());
+    unsafe {
+        ptr.as_ptr()
+            .copy_from_nonoverlapping(input.as_ptr(), input.len());
+    }
+    Ok(())
+}
+
+pub fn read(
+    key_space: u64,
+    key: &[u8],
+    func: impl FnOnce(usize) -> NonNull<u8>,
+) -> Result<Option<Entry>, Error> {
+    let value = with_state(|state| {
+        state
+            .db
+            .db
+            .get(&state.context.contract_address)?
+            .get(&key_space)?
+            .get(key)
+            .cloned()
+    });
+    let value_len = value
+        .as_ref()
+        .map_or(0, |tagged_value| tagged_value.value.len());
+    gas::charge("read", |costs| costs.read, key.len() + value_len);
+    match value {
+        Some(tagged_value) => {
+            let ptr = func(tagged_value.value.len());
+            unsafe {
+                ptr.as_ptr().copy_from_nonoverlapping(
+                    tagged_value.value.as_ptr(),
+                    tagged_value.value.len(),
+                );
+            }
+            Ok(Some(Entry {
+                tag: tagged_value.tag,
+            }))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Makes the `entry_points` callable through [`call`] as a contract at `address`.
+///
+/// Entry points take their arguments through [`copy_input`] and hand results back through
+/// [`ret`], the same way wasm exports do.
+pub fn register_contract(address: Address, entry_points: &[(&str, fn())]) {
+    let contract = NativeContract {
+        entry_points: entry_points
+            .iter()
+            .map(|(name, entry_point)| (name.to_string(), *entry_point))
+            .collect(),
+        package: None,
+    };
+    with_state(|state| state.insert_contract(address, contract));
+}
+
+/// Reads entry point descriptors whose `fptr` points to a `fn()`.
+fn native_entry_points(entry_points: &[EntryPoint]) -> Result<BTreeMap<String, fn()>, Error> {
+    entry_points
+        .iter()
+        .map(|entry_point| {
+            let name = unsafe { slice::from_raw_parts(entry_point.name_ptr, entry_point.name_len) };
+            let name = str::from_utf8(name).map_err(|_| Error::Serialization)?;
+            let fptr = unsafe { mem::transmute::<*const c_void, fn()>(entry_point.fptr) };
+            Ok((name.to_string(), fptr))
+        })
+        .collect()
+}
+
+pub fn create_contract(entry_points: &[EntryPoint]) -> Result<Address, Error> {
+    gas::charge("create_contract", |costs| costs.create_contract, 0);
+    let entry_points = native_entry_points(entry_points)?;
+    let address = with_state(|state| {
+        let address = state.next_address();
+        state.insert_contract(
+            address,
+            NativeContract {
+                entry_points,
+                package: None,
+            },
+        );
+        address
+    });
+    Ok(address)
+}
+
+pub fn create_contract_package() -> Result<Address, Error> {
+    gas::charge("create_contract_package", |costs| costs.manage_package, 0);
+    let address = with_state(|state| {
+        let address = state.next_address();
+        let package = ContractPackage {
+            owner: current_entity(state),
+            versions: Vec::new(),
+        };
+        state.insert_package(address, package);
+        address
+    });
+    Ok(address)
+}
+
+pub fn add_contract_version(
+    package: &Address,
+    entry_points: &[EntryPoint],
+) -> Result<ContractVersion, Error> {
+    gas::charge("add_contract_version", |costs| costs.create_contract, 0);
+    let entry_points = native_entry_points(entry_points)?;
+    with_state(|state| {
+        owned_package(state, package)?;
+        let address = state.next_address();
+        // Already recorded in the journal by `owned_package`.
+        let versions = &mut state.packages.get_mut(package).unwrap().versions;
+        versions.push(PackageVersion {
+            address,
+            enabled: true,
+        });
+        let version = versions.len() as u32;
+        state.insert_contract(
+            address,
+            NativeContract {
+                entry_points,
+                package: Some(*package),
+            },
+        );
+        Ok(ContractVersion { address, version })
+    })
+}
+
+pub fn disable_contract_version(package: &Address, version: u32) -> Result<(), Error> {
+    gas::charge("disable_contract_version", |costs| costs.manage_package, 0);
+    with_state(|state| {
+        let package = owned_package(state, package)?;
+        let index = version.checked_sub(1).ok_or(Error::NotFound)?;
+        let version = package
+            .versions
+            .get_mut(index as usize)
+            .ok_or(Error::NotFound)?;
+        version.enabled = false;
+        Ok(())
+    })
+}
+
+/// Package at `address`, if the executing entity created it.
+fn owned_package<'a>(
+    state: &'a mut State,
+    address: &Address,
+) -> Result<&'a mut ContractPackage, Error> {
+    let current = current_entity(state);
+    match state.packages.get(address) {
+        None => return Err(Error::NotFound),
+        Some(package) if package.owner != current => return Err(Error::PermissionDenied),
+        Some(_) => {}
+    }
+    Ok(state.package_mut(address).unwrap())
+}
+
+/// Finds the contract called through `address` and the address it executes under.
+///
+/// Calling a package resolves to its latest enabled version.
+fn resolve_contract(state: &State, address: &Address) -> Result<(Address, Address), Error> {
+    let package_version = state.packages.get(address).map(|package| {
+        package
+            .versions
+            .iter()
+            .rev()
+            .find(|version| version.enabled)
+            .map(|version| version.address)
+            .ok_or(Error::ContractDisabled)
+    });
+    if let Some(contract_address) = package_version {
+        return Ok((contract_address?, *address));
+    }
+
+    let package = state.contracts.get(address).ok_or(Error::NotFound)?.package;
+    match package {
+        Some(package) => {
+            let enabled = state.packages[&package]
+                .versions
+                .iter()
+                .any(|version| version.address == *address && version.enabled);
+            if !enabled {
+                return Err(Error::ContractDisabled);
+            }
+            Ok((*address, package))
+        }
+        None => Ok((*address, *address)),
+    }
+}
+
+pub fn hash(algorithm: HashAlgorithm, data: &[u8]) -> [u8; 32] {
+    use blake2::{digest::consts::U32, Blake2b};
+    use sha2::{Digest, Sha256};
+    use sha3::Keccak256;
+
+    gas::charge("hash", |costs| costs.hash, data.len());
+
+    match algorithm {
+        HashAlgorithm::Blake2b => Blake2b::<U32>::digest(data).into(),
+        HashAlgorithm::Sha256 => Sha256::digest(data).into(),
+        HashAlgorithm::Keccak256 => Keccak256::digest(data).into(),
+    }
+}
+
+pub fn verify_signature(
+    algorithm: SignatureAlgorithm,
+    message: &[u8],
+    signature: &[u8],
+    public_key: &[u8],
+) -> Result<(), Error> {
+    gas::charge(
+        "verify_signature",
+        |costs| costs.verify_signature,
+        message.len(),
+    );
+    match algorithm {
+        SignatureAlgorithm::Ed25519 => {
+            use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+            let public_key = public_key
+                .try_into()
+                .ok()
+                .and_then(|bytes| VerifyingKey::from_bytes(bytes).ok())
+                .ok_or(Error::InvalidSignature)?;
+            let signature =
+                Signature::from_slice(signature).map_err(|_| Error::InvalidSignature)?;
+            public_key
+                .verify(message, &signature)
+                .map_err(|_| Error::InvalidSignature)
+        }
+        SignatureAlgorithm::Secp256k1 => {
+            use k256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+
+            let public_key =
+                VerifyingKey::from_sec1_bytes(public_key).map_err(|_| Error::InvalidSignature)?;
+            let signature =
+                Signature::from_slice(signature).map_err(|_| Error::InvalidSignature)?;
+            public_key
+                .verify(message, &signature)
+                .map_err(|_| Error::InvalidSignature)
+        }
+    }
+}
+
+/// Entity whose code is currently executing.
+fn current_entity(state: &State) -> Entity {
+    match state.context.contract_address {
+        Some(contract_address) => Entity::Contract(contract_address),
+        None => state.context.caller,
+    }
+}
+
+/// Sets the balance of `entity` in the native ledger.
+pub fn set_balance(entity: Entity, amount: u64) {
+    with_state(|state| state.set_balance(entity, amount));
+}
+
+pub fn balance(entity: Entity) -> u64 {
+    gas::charge("balance", |costs| costs.balance, 0);
+    with_state(|state| state.balances.get(&entity).copied().unwrap_or_default())
+}
+
+fn move_balance(
+    state: &mut State,
+    source: Entity,
+    target: Entity,
+    amount: u64,
+) -> Result<(), Error> {
+    let balance = |entity| state.balances.get(&entity).copied().unwrap_or_default();
+    let source_balance = balance(source)
+        .checked_sub(amount)
+        .ok_or(Error::InsufficientFunds)?;
+    if source == target {
+        return Ok(());
+    }
+    let target_balance = balance(target)
+        .checked_add(amount)
+        .ok_or(Error::BalanceOverflow)?;
+    state.set_balance(source, source_balance);
+    state.set_balance(target, target_balance);
+    Ok(())
+}
+
+pub fn transfer_to_account(address: &Address, amount: u64) -> Result<(), Error> {
+    gas::charge("transfer_to_account", |costs| costs.transfer, 0);
+    with_state(|state| {
+        let source = current_entity(state);
+        move_balance(state, source, Entity::Account(*address), amount)
+    })
+}
+
+/// Transfers to the contract, or to the package it is a version of.
+pub fn transfer_to_contract(address: &Address, amount: u64) -> Result<(), Error> {
+    gas::charge("transfer_to_contract", |costs| costs.transfer, 0);
+    with_state(|state| {
+        let (_, executes_as) = resolve_contract(state, address)?;
+        let source = current_entity(state);
+        move_balance(state, source, Entity::Contract(executes_as), amount)
+    })
+}
+
+pub fn call(
+    address: &Address,
+    entry_point: &str,
+    input: &[u8],
+    value: u64,
+    func: impl FnOnce(usize) -> NonNull<u8>,
+) -> Result<(), Error> {
+    // Held until the caller's frame is back in place.
+    let _calls = lock_calls();
+    gas::charge("call", |costs| costs.call, input.len());
+    let args = if input.is_empty() {
+        RuntimeArgs::new()
+    } else {
+        RuntimeArgs::try_from_slice(input).map_err(|_| Error::Serialization)?
+    };
+
+    let (export, contract_address, executes_as) = with_state(|state| {
+        let (contract_address, executes_as) = resolve_contract(state, address)?;
+        let export = state
+            .contracts
+            .get(&contract_address)
+            .and_then(|contract| contract.entry_points.get(entry_point).copied())
+            .ok_or(Error::NotFound)?;
+        Ok((export, contract_address, executes_as))
+    })?;
+
+    // Discards storage writes and transfers of the callee, including the attached tokens, if
+    // it reverts.
+    let mut caller_frame = None;
+    let result = transaction(|| {
+        with_state(|state| {
+            let caller = current_entity(state);
+            move_balance(state, caller, Entity::Contract(executes_as), value)?;
+            let callee_context = Context {
+                caller,
+                contract_address: Some(executes_as),
+                attached_value: value,
+                ..state.context.clone()
+            };
+            caller_frame = Some((
+                mem::replace(&mut state.context, callee_context),
+                mem::replace(&mut state.args, args),
+                state.return_data.take(),
+            ));
+            state.meter.enter_call(contract_address, entry_point);
+            Ok(())
+        })?;
+        export();
+        Ok(())
+    });
+    let Some((caller_context, caller_args, caller_return_data)) = caller_frame else {
+        // The attached value couldn't be transferred, so the callee never started.
+        return result.unwrap_or_else(|payload| panic::resume_unwind(payload));
+    };
+
+    let (return_data, out_of_gas, nested) = with_state(|state| {
+        state.meter.exit_call();
+        state.context = caller_context;
+        state.args = caller_args;
+        let return_data = mem::replace(&mut state.return_data, caller_return_data);
+        (
+            return_data,
+            state.meter.is_exhausted(),
+            state.meter.in_call(),
+        )
+    });
+
+    if let Err(payload) = result {
+        match (out_of_gas, nested) {
+            // Running out of gas reverts every caller, not just the callee.
+            (true, true) => panic::resume_unwind(payload),
+            (true, false) => return Err(Error::OutOfGas),
+            (false, _) => return Err(Error::CalleeReverted),
+        }
+    }
+    if let Some(return_data) = return_data {
+        let ptr = func(return_data.len());
+        unsafe {
+            ptr.as_ptr()
+                .copy_from_nonoverlapping(return_data.as_ptr(), return_data.len());
+        }
+    }
+    Ok(())
+}
+
+// pub fn dispatch<Args, R>(export: impl Fn(Args) -> R, args: Args) -> R {
+//     export(args)
+// }
+/// Unwinds to the closest [`call`] with a [`Revert::Code`] payload.
+pub fn revert(code: u32) -> ! {
+    panic::panic_any(Revert::Code(code))
+}
+
+pub fn ret(data: &[u8]) {
+    gas::charge("ret", |costs| costs.ret, data.len());
+    with_state(|state| state.return_data = Some(Bytes::copy_from_slice(data)));
+}
+
+/// Takes the bytes passed to the last [`ret`] call, if any.
+pub fn take_return_data() -> Option<Bytes> {
+    with_state(|state| state.return_data.take())
+}
+
+/// Makes `f` callable by name through the dispatcher of the current [`TestEnv`].
+pub(crate) fn register_function(name: &str, f: fn()) {
+    with_state(|state| state.functions.insert(name.to_string(), f));
+}
+
+pub(crate) fn registered_function(name: &str) -> Option<fn()> {
+    with_state(|state| state.functions.get(name).copied())
+}
@@ -0,0 +1,415 @@
+//! Local chains the native host functions execute against.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs, io, mem, panic,
+    path::Path,
+    sync::{Arc, Condvar, Mutex, PoisonError},
+    thread::{self, ThreadId},
+};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{ApiError, Contract, RuntimeArgs};
+
+use super::{
+    super::{Address, Entity, Error},
+    gas::Meter,
+    journal::Journal,
+    Context, ContractPackage, LocalKV, NativeContract,
+};
+
+/// Everything a [`TestEnv`] owns.
+#[derive(Default, Clone)]
+pub(super) struct State {
+    pub(super) db: LocalKV,
+    /// Named arguments of the current call.
+    pub(super) args: RuntimeArgs,
+    pub(super) return_data: Option<Bytes>,
+    pub(super) context: Context,
+    pub(super) contracts: BTreeMap<Address, NativeContract>,
+    pub(super) packages: BTreeMap<Address, ContractPackage>,
+    /// Number of contracts and packages created so far, used to derive their addresses.
+    pub(super) created_contracts: u64,
+    pub(super) balances: BTreeMap<Entity, u64>,
+    /// Functions registered with [`crate::register_func`].
+    pub(super) functions: BTreeMap<String, fn()>,
+    pub(super) meter: Meter,
+    pub(super) journal: Journal,
+    /// Messages passed to [`super::print`], oldest first.
+    pub(super) printed: Vec<String>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<TestEnv> = RefCell::new(TestEnv::new());
+}
+
+/// Runs `f` on the state of the env the host functions of this thread route to.
+///
+/// `f` must not call back into host functions, since the state stays locked while it runs.
+pub(super) fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    CURRENT.with(|env| {
+        let env = env.borrow();
+        let mut state = env.state.lock().unwrap_or_else(PoisonError::into_inner);
+        f(&mut state)
+    })
+}
+
+/// Lets one thread at a time execute calls in an env, so threads sharing it don't interleave
+/// their callers, arguments, return data and journals.
+///
+/// The lock is reentrant, which lets the thread holding it make nested calls.
+#[derive(Default)]
+struct CallLock {
+    /// Thread holding the lock and how many times it took it.
+    owner: Mutex<(Option<ThreadId>, usize)>,
+    released: Condvar,
+}
+
+/// Lets other threads execute calls in the env again when dropped.
+pub(super) struct CallGuard {
+    lock: Arc<CallLock>,
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        let mut owner = self
+            .lock
+            .owner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        owner.1 -= 1;
+        if owner.1 == 0 {
+            owner.0 = None;
+            self.lock.released.notify_one();
+        }
+    }
+}
+
+/// Waits until no other thread is executing a call in the env the host functions of this thread
+/// route to, then keeps other threads from starting one until the guard is dropped.
+///
+/// Taken before the state is locked, and never while it is.
+pub(super) fn lock_calls() -> CallGuard {
+    let lock = CURRENT.with(|env| env.borrow().calls.clone());
+    let thread = thread::current().id();
+    let mut owner = lock.owner.lock().unwrap_or_else(PoisonError::into_inner);
+    while owner.0.is_some_and(|owner| owner != thread) {
+        owner = lock
+            .released
+            .wait(owner)
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+    owner.0 = Some(thread);
+    owner.1 += 1;
+    drop(owner);
+    CallGuard { lock }
+}
+
+/// A value stored by a contract, as seen from outside the contract.
+///
+/// Byte strings are hex-encoded in JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
+pub struct StorageEntry {
+    /// Contract the value belongs to; `None` for code executed outside of a contract.
+    #[serde(with = "hex_address")]
+    pub contract: Option<Address>,
+    pub key_space: u64,
+    #[serde(with = "hex::serde")]
+    pub key: Vec<u8>,
+    pub tag: u64,
+    #[serde(with = "hex::serde")]
+    pub value: Vec<u8>,
+}
+
+mod hex_address {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use super::Address;
+
+    pub(super) fn serialize<S: Serializer>(
+        address: &Option<Address>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match address {
+            Some(address) => serializer.serialize_some(&hex::encode(address)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Address>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|address| hex::FromHex::from_hex(address).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+/// Encoding of a state file written by [`TestEnv::save`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateFormat {
+    /// Pretty-printed JSON, for fixtures meant to be read and edited by hand.
+    Json,
+    /// Borsh, for large states.
+    Borsh,
+}
+
+impl StateFormat {
+    /// Picks the format from the extension of `path`: JSON for `.json`, Borsh otherwise.
+    pub fn from_path(path: impl AsRef<Path>) -> Self {
+        match path.as_ref().extension() {
+            Some(extension) if extension == "json" => StateFormat::Json,
+            _ => StateFormat::Borsh,
+        }
+    }
+}
+
+/// Contents of a state file.
+#[derive(Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
+struct StateFile {
+    /// Contract the context of the env runs as.
+    #[serde(default, with = "hex_address")]
+    contract: Option<Address>,
+    storage: Vec<StorageEntry>,
+}
+
+/// Local chain holding storage, balances, installed contracts and the execution context.
+///
+/// Host functions route to the env [entered](TestEnv::enter) on the calling thread. Every thread
+/// starts out in its own fresh env, so tests don't see each other's state. Clones share the same
+/// chain, which lets a test inspect an env from the outside or enter it from several threads.
+/// Calls made from several threads run one after the other, so a call must not wait on another
+/// thread calling into the same env.
+#[derive(Clone, Default)]
+pub struct TestEnv {
+    state: Arc<Mutex<State>>,
+    calls: Arc<CallLock>,
+}
+
+/// Routes the host functions of this thread back to the previous env when dropped.
+#[must_use]
+pub struct EnvGuard {
+    previous: Option<TestEnv>,
+}
+
+impl Drop for EnvGuard {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            CURRENT.with(|env| *env.borrow_mut() = previous);
+        }
+    }
+}
+
+impl TestEnv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The env host functions of this thread currently route to.
+    pub fn current() -> Self {
+        CURRENT.with(|env| env.borrow().clone())
+    }
+
+    /// Routes the host functions of this thread to this env until the guard is dropped.
+    pub fn enter(&self) -> EnvGuard {
+        let previous = CURRENT.with(|env| env.replace(self.clone()));
+        EnvGuard {
+            previous: Some(previous),
+        }
+    }
+
+    /// Runs `f` with the host functions of this thread routed to this env.
+    pub fn run<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _guard = self.enter();
+        f()
+    }
+
+    /// Creates an independent env starting from a copy of this one.
+    pub fn fork(&self) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(self.with_state(|state| state.clone()))),
+            calls: Arc::default(),
+        }
+    }
+
+    fn with_state<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        f(&mut state)
+    }
+
+    /// Installs `C` as a new contract, as [`Contract::install`] would on chain.
+    pub fn deploy<C: Contract>(&self) -> Result<Address, Error> {
+        self.run(C::install)
+    }
+
+    /// Calls `entry_point` of the contract at `address` with the Borsh-encoded `args`.
+    pub fn call<R: BorshDeserialize>(
+        &self,
+        address: Address,
+        entry_point: &str,
+        args: RuntimeArgs,
+    ) -> Result<R, ApiError> {
+        self.run(|| crate::call_contract(address, entry_point, args))
+    }
+
+    /// Calls the function registered as `name` with `args`, as [`crate::dispatch`] would, and
+    /// decodes the value it returns.
+    ///
+    /// Unlike [`TestEnv::call`], the function runs outside of any contract, and reverts unwind
+    /// into the caller after its changes to the env are undone.
+    pub fn dispatch<R: BorshDeserialize>(
+        &self,
+        name: &str,
+        args: RuntimeArgs,
+    ) -> Result<R, ApiError> {
+        self.run(|| {
+            let _calls = lock_calls();
+            let caller_args = self.with_state(|state| mem::replace(&mut state.args, args));
+            let result = super::transaction(|| crate::dispatch(name));
+            // Put back before resuming a revert, so the env doesn't keep the dispatched args.
+            let return_data = self.with_state(|state| {
+                state.args = caller_args;
+                state.return_data.take()
+            });
+            result.unwrap_or_else(|payload| panic::resume_unwind(payload))?;
+            R::try_from_slice(return_data.as_deref().unwrap_or_default()).map_err(ApiError::Io)
+        })
+    }
+
+    pub fn context(&self) -> Context {
+        self.with_state(|state| state.context.clone())
+    }
+
+    pub fn set_context(&self, context: Context) {
+        self.with_state(|state| state.context = context);
+    }
+
+    /// Sets the named arguments returned by [`crate::named_args`] outside of a call.
+    pub fn set_args(&self, args: RuntimeArgs) {
+        self.with_state(|state| state.args = args);
+    }
+
+    pub fn balance(&self, entity: Entity) -> u64 {
+        self.with_state(|state| state.balances.get(&entity).copied().unwrap_or_default())
+    }
+
+    pub fn set_balance(&self, entity: Entity, amount: u64) {
+        self.with_state(|state| state.set_balance(entity, amount));
+    }
+
+    /// Reads the tag and value `contract` stored under `key`.
+    pub fn read(
+        &self,
+        contract: Option<Address>,
+        key_space: u64,
+        key: &[u8],
+    ) -> Option<(u64, Vec<u8>)> {
+        self.with_state(|state| {
+            let tagged_value = state.db.db.get(&contract)?.get(&key_space)?.get(key)?;
+            Some((tagged_value.tag, tagged_value.value.to_vec()))
+        })
+    }
+
+    /// Every value in storage, ordered by contract, key space and key.
+    pub fn storage(&self) -> Vec<StorageEntry> {
+        self.with_state(|state| {
+            let mut entries = Vec::new();
+            for (contract, container) in &state.db.db {
+                for (key_space, values) in container {
+                    for (key, tagged_value) in values {
+                        entries.push(StorageEntry {
+                            contract: *contract,
+                            key_space: *key_space,
+                            key: key.to_vec(),
+                            tag: tagged_value.tag,
+                            value: tagged_value.value.to_vec(),
+                        });
+                    }
+                }
+            }
+            entries
+        })
+    }
+
+    /// Entry point `name` of the contract installed at `address`.
+    ///
+    /// The entry point reads its arguments and the contract it executes as from this env, like
+    /// any other code run in it.
+    pub fn entry_point(&self, address: Address, name: &str) -> Option<fn()> {
+        self.with_state(|state| {
+            state
+                .contracts
+                .get(&address)?
+                .entry_points
+                .get(name)
+                .copied()
+        })
+    }
+
+    /// Messages printed so far, oldest first.
+    pub fn printed(&self) -> Vec<String> {
+        self.with_state(|state| state.printed.clone())
+    }
+
+    /// Stores `value` under `key` for `contract`, as if the contract wrote it.
+    pub fn write(
+        &self,
+        contract: Option<Address>,
+        key_space: u64,
+        key: &[u8],
+        tag: u64,
+        value: &[u8],
+    ) {
+        self.with_state(|state| state.insert_value(contract, key_space, key, tag, value));
+    }
+
+    /// Writes the storage of this env to `path`, along with the contract address of its context.
+    ///
+    /// Only storage is saved; contracts hold native function pointers and have to be installed
+    /// again by the code that loads the state.
+    pub fn save(&self, path: impl AsRef<Path>, format: StateFormat) -> io::Result<()> {
+        let file = StateFile {
+            contract: self.context().contract_address,
+            storage: self.storage(),
+        };
+        let bytes = match format {
+            StateFormat::Json => serde_json::to_vec_pretty(&file)?,
+            StateFormat::Borsh => borsh::to_vec(&file)?,
+        };
+        fs::write(path, bytes)
+    }
+
+    /// Creates an env whose storage and context contract address are read from a file written
+    /// by [`TestEnv::save`].
+    pub fn load(path: impl AsRef<Path>, format: StateFormat) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        let file: StateFile = match format {
+            StateFormat::Json => serde_json::from_slice(&bytes)?,
+            StateFormat::Borsh => StateFile::try_from_slice(&bytes)?,
+        };
+        let env = Self::new();
+        env.set_context(Context {
+            contract_address: file.contract,
+            ..env.context()
+        });
+        for entry in file.storage {
+            env.write(
+                entry.contract,
+                entry.key_space,
+                &entry.key,
+                entry.tag,
+                &entry.value,
+            );
+        }
+        Ok(env)
+    }
+
+    /// Names of the functions registered with [`crate::register_func`].
+    pub fn functions(&self) -> Vec<String> {
+        self.with_state(|state| state.functions.keys().cloned().collect())
+    }
+}
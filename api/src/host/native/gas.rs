@@ -0,0 +1,188 @@
+//! Gas metering of the native host functions.
+
+use std::{collections::BTreeMap, mem, panic};
+
+use crate::testing::Revert;
+
+use super::{super::Address, with_state};
+
+/// Gas charged for one call of a host function.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Cost {
+    pub base: u64,
+    /// Charged for every byte passed to or returned from the host function.
+    pub per_byte: u64,
+}
+
+impl Cost {
+    pub const fn new(base: u64, per_byte: u64) -> Self {
+        Self { base, per_byte }
+    }
+
+    fn of(&self, bytes: usize) -> u64 {
+        self.per_byte
+            .saturating_mul(bytes as u64)
+            .saturating_add(self.base)
+    }
+}
+
+/// Cost of every host function of the native backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CostTable {
+    /// Charged per byte of key and value read.
+    pub read: Cost,
+    /// Charged per byte of key and value written.
+    pub write: Cost,
+    pub print: Cost,
+    pub copy_input: Cost,
+    pub ret: Cost,
+    /// Charged per byte of input, on top of whatever the callee spends.
+    pub call: Cost,
+    /// Charged when creating a contract or adding a contract version.
+    pub create_contract: Cost,
+    /// Charged when creating a package or disabling one of its versions.
+    pub manage_package: Cost,
+    pub transfer: Cost,
+    pub balance: Cost,
+    pub hash: Cost,
+    /// Charged per byte of the signed message.
+    pub verify_signature: Cost,
+    /// Charged by the getters of the execution context, like `caller` or `block_time`.
+    pub env: Cost,
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        Self {
+            read: Cost::new(1_000, 10),
+            write: Cost::new(10_000, 100),
+            print: Cost::new(100, 1),
+            copy_input: Cost::new(100, 1),
+            ret: Cost::new(100, 1),
+            call: Cost::new(10_000, 1),
+            create_contract: Cost::new(100_000, 0),
+            manage_package: Cost::new(10_000, 0),
+            transfer: Cost::new(10_000, 0),
+            balance: Cost::new(1_000, 0),
+            hash: Cost::new(1_000, 10),
+            verify_signature: Cost::new(50_000, 10),
+            env: Cost::new(100, 0),
+        }
+    }
+}
+
+/// Gas spent by one call made through [`super::call`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GasReport {
+    pub address: Address,
+    pub entry_point: String,
+    /// Total gas spent by the call, including nested calls.
+    pub gas_used: u64,
+    /// Gas spent in each host function, by name.
+    pub host_functions: BTreeMap<&'static str, u64>,
+}
+
+/// Gas accounting of a [`super::TestEnv`].
+#[derive(Default, Clone)]
+pub(super) struct Meter {
+    costs: CostTable,
+    limit: Option<u64>,
+    used: u64,
+    exhausted: bool,
+    /// Reports of the calls currently executing, innermost last.
+    open: Vec<GasReport>,
+    finished: Vec<GasReport>,
+}
+
+impl Meter {
+    /// Adds `amount` to the used gas, returning `false` if that exceeds the gas limit.
+    fn charge(&mut self, host_function: &'static str, mut amount: u64) -> bool {
+        let used = self.used.saturating_add(amount);
+        if let Some(limit) = self.limit.filter(|limit| used > *limit) {
+            amount = limit.saturating_sub(self.used);
+            self.exhausted = true;
+        }
+        self.used += amount;
+        for report in &mut self.open {
+            report.gas_used += amount;
+            *report.host_functions.entry(host_function).or_default() += amount;
+        }
+        !self.exhausted
+    }
+
+    /// Whether execution was reverted because it exceeded the gas limit.
+    pub(super) fn is_exhausted(&self) -> bool {
+        self.exhausted
+    }
+
+    /// Whether a call made through [`super::call`] is executing.
+    pub(super) fn in_call(&self) -> bool {
+        !self.open.is_empty()
+    }
+
+    pub(super) fn enter_call(&mut self, address: Address, entry_point: &str) {
+        self.open.push(GasReport {
+            address,
+            entry_point: entry_point.to_string(),
+            gas_used: 0,
+            host_functions: BTreeMap::new(),
+        });
+    }
+
+    pub(super) fn exit_call(&mut self) {
+        if let Some(report) = self.open.pop() {
+            self.finished.push(report);
+        }
+    }
+}
+
+pub fn set_cost_table(costs: CostTable) {
+    with_state(|state| state.meter.costs = costs);
+}
+
+pub fn cost_table() -> CostTable {
+    with_state(|state| state.meter.costs.clone())
+}
+
+/// Limits the gas that can be used in the current env; `None` disables the limit.
+///
+/// Host functions exceeding the limit revert the whole execution, including every caller. The
+/// outermost [`super::call`] then fails with [`Error::OutOfGas`](super::Error::OutOfGas).
+pub fn set_gas_limit(limit: Option<u64>) {
+    with_state(|state| {
+        state.meter.limit = limit;
+        state.meter.exhausted = false;
+    });
+}
+
+/// Gas used in the current env since the last [`reset_gas`].
+pub fn gas_used() -> u64 {
+    with_state(|state| state.meter.used)
+}
+
+/// Zeroes the used gas and drops the collected reports. The cost table and limit are kept.
+pub fn reset_gas() {
+    with_state(|state| {
+        let meter = &mut state.meter;
+        meter.used = 0;
+        meter.exhausted = false;
+        meter.open.clear();
+        meter.finished.clear();
+    });
+}
+
+/// Takes the reports of the calls finished since the last call, in the order they finished.
+pub fn take_gas_reports() -> Vec<GasReport> {
+    with_state(|state| mem::take(&mut state.meter.finished))
+}
+
+/// Charges `host_function` for handling `bytes` bytes, reverting if the gas limit is exceeded.
+pub(super) fn charge(host_function: &'static str, cost: fn(&CostTable) -> Cost, bytes: usize) {
+    let charged = with_state(|state| {
+        let amount = cost(&state.meter.costs).of(bytes);
+        state.meter.charge(host_function, amount)
+    });
+    if !charged {
+        panic::panic_any(Revert::OutOfGas);
+    }
+}
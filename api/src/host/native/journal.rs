@@ -0,0 +1,180 @@
+//! Changes made to a [`TestEnv`](super::TestEnv) by calls that may still revert.
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    thread,
+};
+
+use bytes::Bytes;
+
+use super::{
+    super::{Address, Entity},
+    env::{lock_calls, with_state, State},
+    ContractPackage, NativeContract, TaggedValue,
+};
+
+/// A change to the state, holding what it replaced so it can be undone.
+#[derive(Clone)]
+enum Change {
+    Value {
+        contract: Option<Address>,
+        key_space: u64,
+        key: Bytes,
+        previous: Option<TaggedValue>,
+    },
+    Balance {
+        entity: Entity,
+        previous: Option<u64>,
+    },
+    Contract {
+        address: Address,
+        previous: Option<NativeContract>,
+    },
+    Package {
+        address: Address,
+        previous: Option<ContractPackage>,
+    },
+    CreatedContracts(u64),
+}
+
+/// Changes made since the outermost open [`transaction`], oldest first.
+///
+/// Nothing is recorded outside of transactions, and the journal is emptied once the outermost
+/// one finishes.
+#[derive(Default, Clone)]
+pub(super) struct Journal {
+    open: usize,
+    changes: Vec<Change>,
+}
+
+impl Journal {
+    fn record(&mut self, change: impl FnOnce() -> Change) {
+        if self.open > 0 {
+            self.changes.push(change());
+        }
+    }
+}
+
+impl State {
+    pub(super) fn insert_value(
+        &mut self,
+        contract: Option<Address>,
+        key_space: u64,
+        key: &[u8],
+        tag: u64,
+        value: &[u8],
+    ) {
+        let previous = self.db.insert(contract, key_space, key, tag, value);
+        self.journal.record(|| Change::Value {
+            contract,
+            key_space,
+            key: Bytes::copy_from_slice(key),
+            previous,
+        });
+    }
+
+    pub(super) fn set_balance(&mut self, entity: Entity, amount: u64) {
+        let previous = self.balances.insert(entity, amount);
+        self.journal.record(|| Change::Balance { entity, previous });
+    }
+
+    pub(super) fn insert_contract(&mut self, address: Address, contract: NativeContract) {
+        let previous = self.contracts.insert(address, contract);
+        self.journal
+            .record(|| Change::Contract { address, previous });
+    }
+
+    pub(super) fn insert_package(&mut self, address: Address, package: ContractPackage) {
+        let previous = self.packages.insert(address, package);
+        self.journal
+            .record(|| Change::Package { address, previous });
+    }
+
+    /// The package at `address`, recorded as changed.
+    pub(super) fn package_mut(&mut self, address: &Address) -> Option<&mut ContractPackage> {
+        let package = self.packages.get_mut(address)?;
+        self.journal.record(|| Change::Package {
+            address: *address,
+            previous: Some(package.clone()),
+        });
+        Some(package)
+    }
+
+    /// Derives the address of the next contract or package to be created.
+    pub(super) fn next_address(&mut self) -> Address {
+        let previous = self.created_contracts;
+        self.journal.record(|| Change::CreatedContracts(previous));
+        self.created_contracts += 1;
+        let mut address = [0; 32];
+        address[..8].copy_from_slice(&self.created_contracts.to_be_bytes());
+        address
+    }
+
+    fn undo(&mut self, change: Change) {
+        match change {
+            Change::Value {
+                contract,
+                key_space,
+                key,
+                previous,
+            } => {
+                let values = self
+                    .db
+                    .db
+                    .entry(contract)
+                    .or_default()
+                    .entry(key_space)
+                    .or_default();
+                match previous {
+                    Some(previous) => values.insert(key, previous),
+                    None => values.remove(&key),
+                };
+            }
+            Change::Balance { entity, previous } => {
+                match previous {
+                    Some(previous) => self.balances.insert(entity, previous),
+                    None => self.balances.remove(&entity),
+                };
+            }
+            Change::Contract { address, previous } => {
+                match previous {
+                    Some(previous) => self.contracts.insert(address, previous),
+                    None => self.contracts.remove(&address),
+                };
+            }
+            Change::Package { address, previous } => {
+                match previous {
+                    Some(previous) => self.packages.insert(address, previous),
+                    None => self.packages.remove(&address),
+                };
+            }
+            Change::CreatedContracts(previous) => self.created_contracts = previous,
+        }
+    }
+}
+
+/// Runs `f` on the current env as one transaction: if it unwinds, every change it made to
+/// storage, balances, contracts and packages is undone before the payload is returned.
+///
+/// Transactions nest, so a reverted inner call only undoes its own changes.
+pub(crate) fn transaction<R>(f: impl FnOnce() -> R) -> thread::Result<R> {
+    let _calls = lock_calls();
+    let checkpoint = with_state(|state| {
+        state.journal.open += 1;
+        state.journal.changes.len()
+    });
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    with_state(|state| {
+        if result.is_err() {
+            let changes = state.journal.changes.split_off(checkpoint);
+            for change in changes.into_iter().rev() {
+                state.undo(change);
+            }
+        }
+        state.journal.open -= 1;
+        if state.journal.open == 0 {
+            state.journal.changes.clear();
+        }
+    });
+    result
+}
@@ -1,41 +1,20 @@
 // #![feature(wasm_import_memory)]
 // #[linkage = "--import-memory"]
 
+pub mod cl_type;
 pub mod host;
+mod runtime_args;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod testing;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod transcode;
 
-use std::{cell::RefCell, collections::BTreeMap, fmt, io, marker::PhantomData, ptr::NonNull};
-
-use borsh::{BorshDeserialize, BorshSerialize};
-
-#[derive(Debug)]
-#[cfg_attr(not(target_arch = "wasm32"), derive(Serialize, Deserialize))]
-pub enum CLType {
-    Bool,
-    String,
-    Unit,
-    Any,
-}
+pub use cl_type::{CLType, CLTyped};
+pub use runtime_args::{RuntimeArg, RuntimeArgs};
 
-pub trait CLTyped {
-    fn cl_type() -> CLType;
-}
+use std::{fmt, io, marker::PhantomData, ptr::NonNull};
 
-impl CLTyped for String {
-    fn cl_type() -> CLType {
-        CLType::String
-    }
-}
-impl CLTyped for bool {
-    fn cl_type() -> CLType {
-        CLType::Bool
-    }
-}
-
-impl CLTyped for () {
-    fn cl_type() -> CLType {
-        CLType::Unit
-    }
-}
+use borsh::{BorshDeserialize, BorshSerialize};
 
 #[cfg(not(target_arch = "wasm32"))]
 use serde::{Serialize, Deserialize};
@@ -44,14 +23,21 @@ use serde::{Serialize, Deserialize};
 pub struct SchemaArgument {
     pub name: &'static str,
     pub ty: CLType,
+    /// Whether the argument can be left out, which it can if it is an `Option` or has a default.
+    pub optional: bool,
+    /// Source of the expression the argument defaults to, from `#[casper(default = ..)]`.
+    pub default: Option<&'static str>,
 }
 
 #[derive(Debug)]
 #[cfg_attr(not(target_arch = "wasm32"), derive(Serialize, Deserialize))]
-
 pub struct SchemaEntryPoint {
     pub name: &'static str,
     pub arguments: Vec<SchemaArgument>,
+    /// Type of the value the entry point returns.
+    pub result: CLType,
+    /// Whether tokens can be attached to calls of this entry point.
+    pub payable: bool,
 }
 
 #[derive(Debug)]
@@ -100,38 +86,88 @@ impl<T> Value<T> {
     }
 }
 
-impl<T: BorshSerialize> Value<T> {
-    pub fn set(&mut self, value: T) -> io::Result<()> {
-        // let mut value = Vec::new();
-        // value.serialize(&mut value)?;
-        let v = borsh::to_vec(&value)?;
-        host::write(self.key_space, self.name.as_bytes(), 0, &v)
-            .map_err(|error| io::Error::new(io::ErrorKind::Other, "todo"))?;
-        Ok(())
+impl<T: BorshSerialize + CLTyped> Value<T> {
+    pub fn set(&mut self, value: T) -> Result<(), host::Error> {
+        let v = borsh::to_vec(&value).map_err(|_| host::Error::Serialization)?;
+        host::write(self.key_space, self.name.as_bytes(), T::cl_type().tag(), &v)
     }
 }
 impl<T: BorshDeserialize> Value<T> {
-    pub fn get(&self) -> io::Result<Option<T>> {
-        let mut read = None;//Vec::new();
+    pub fn get(&self) -> Result<Option<T>, host::Error> {
+        let mut read = None;
         host::read(self.key_space, self.name.as_bytes(), |size| {
-            *(&mut read) = Some(Vec::new());
-            reserve_vec_space(read.as_mut().unwrap(), size)
-        })
-        .map_err(|error| io::Error::new(io::ErrorKind::Other, "todo"))?;
+            reserve_vec_space(read.insert(Vec::new()), size)
+        })?;
         match read {
-            Some(mut read) => {
-                let value = T::deserialize(&mut read.as_slice())?;
+            Some(read) => {
+                let value =
+                    T::deserialize(&mut read.as_slice()).map_err(|_| host::Error::Serialization)?;
                 Ok(Some(value))
-            },
+            }
             None => Ok(None),
         }
     }
 }
 
+/// Borsh-encodes `value` and hands it back to the caller as the result of the entry point.
+pub fn ret<T: BorshSerialize>(value: &T) -> Result<(), host::Error> {
+    let bytes = borsh::to_vec(value).map_err(|_| host::Error::Serialization)?;
+    host::ret(&bytes);
+    Ok(())
+}
+
+/// Revert code of entry points called with attached tokens they don't accept.
+pub const REVERT_NOT_PAYABLE: u32 = 0xFFFF_FF00;
+
+/// Calls `entry_point` of the contract at `address` and decodes its result.
+pub fn call_contract<R: BorshDeserialize>(
+    address: host::Address,
+    entry_point: &str,
+    args: RuntimeArgs,
+) -> Result<R, ApiError> {
+    call_contract_with_value(address, entry_point, args, 0)
+}
+
+/// Calls a payable `entry_point`, transferring `amount` from the caller to the contract.
+pub fn call_contract_with_value<R: BorshDeserialize>(
+    address: host::Address,
+    entry_point: &str,
+    args: RuntimeArgs,
+    amount: u64,
+) -> Result<R, ApiError> {
+    let input = borsh::to_vec(&args).map_err(ApiError::Io)?;
+    let mut output = Vec::new();
+    host::call(&address, entry_point, &input, amount, |size| {
+        reserve_vec_space(&mut output, size)
+    })
+    .map_err(ApiError::Host)?;
+    R::try_from_slice(&output).map_err(ApiError::Io)
+}
+
+/// Amount of tokens attached to the current call.
+pub fn attached_value() -> u64 {
+    host::attached_value()
+}
+
+#[doc(hidden)]
+pub mod __private {
+    pub use std::borrow::{Borrow, ToOwned};
+}
+
 pub trait Contract {
     fn new() -> Self;
     fn name() -> &'static str;
     fn schema() -> Schema;
+    /// Creates a new contract exposing the entry points of this contract.
+    fn install() -> Result<host::Address, host::Error>;
+    /// Adds the entry points of this contract as a new version of `package`.
+    fn install_version(package: &host::Address) -> Result<host::ContractVersion, host::Error>;
+    /// Registers the entry points of this contract with [`register_func`], so [`dispatch`] can
+    /// call them by name.
+    ///
+    /// On wasm, the exports generated by `#[casper(entry_points)]` call this before dispatching
+    /// to their entry point.
+    fn register();
 }
 
 #[derive(Debug)]
@@ -143,7 +179,7 @@ pub enum Access {
 #[derive(Debug)]
 pub struct EntryPoint<'a, F: Fn()> {
     pub name: &'a str,
-    pub params: &'a [(&'a str, CLType)],
+    pub params: &'a [SchemaArgument],
     // pub access: Access,
     // fptr: extern "C" fn() -> (),
     pub func: F,
@@ -154,18 +190,50 @@ pub enum ApiError {
     Error1,
     Error2,
     MissingArgument,
+    /// Argument `name` was passed with type `got`, but decoded as `expected`.
+    InvalidArgumentType {
+        name: String,
+        expected: CLType,
+        got: CLType,
+    },
+    Host(host::Error),
     Io(io::Error),
 }
 
+/// Function called by the dispatcher. Natively it is a plain Rust function, so reverts can
+/// unwind through it.
+#[cfg(target_arch = "wasm32")]
+pub type DispatchFn = extern "C" fn();
+#[cfg(not(target_arch = "wasm32"))]
+pub type DispatchFn = fn();
+
+// Natively, registered functions live in the current `host::TestEnv`. On wasm the table holds
+// what the contract registers itself, which the exports generated by `#[casper(entry_points)]`
+// do before dispatching through it.
+#[cfg(target_arch = "wasm32")]
 thread_local! {
-    pub static DISPATCHER: RefCell<BTreeMap<String, extern "C" fn()>> = Default::default();
-    pub static ARGS: RefCell<BTreeMap<String, Vec<u8>>> = Default::default();
+    pub static DISPATCHER: std::cell::RefCell<std::collections::BTreeMap<String, DispatchFn>> = Default::default();
 }
 
+/// # Safety
+///
+/// `f` is called by the dispatcher without arguments, so it has to fetch its input from the host.
 #[no_mangle]
-pub unsafe fn register_func(name: &str, f: extern "C" fn() -> ()) {
-    println!("registering function {}", name);
-    DISPATCHER.with(|foo| foo.borrow_mut().insert(name.to_string(), f));
+pub unsafe fn register_func(name: &str, f: DispatchFn) {
+    #[cfg(target_arch = "wasm32")]
+    DISPATCHER.with(|dispatcher| dispatcher.borrow_mut().insert(name.to_string(), f));
+    #[cfg(not(target_arch = "wasm32"))]
+    host::register_function(name, f);
+}
+
+/// Calls the function registered as `name`, which gets the named arguments of the current call.
+pub fn dispatch(name: &str) -> Result<(), ApiError> {
+    #[cfg(target_arch = "wasm32")]
+    let f = DISPATCHER.with(|dispatcher| dispatcher.borrow().get(name).copied());
+    #[cfg(not(target_arch = "wasm32"))]
+    let f = host::registered_function(name);
+    f.ok_or(ApiError::Host(host::Error::NotFound))?();
+    Ok(())
 }
 
 pub fn register_entrypoint<'a, F: fmt::Debug + Fn()>(entrypoint: EntryPoint<'a, F>) {
@@ -176,12 +244,58 @@ pub fn register_entrypoint<'a, F: fmt::Debug + Fn()>(entrypoint: EntryPoint<'a,
     // }
 }
 
-pub fn get_named_arg<T: BorshDeserialize>(name: &str) -> Result<T, ApiError> {
-    let arg_bytes = ARGS
-        .with(|args| args.borrow().get(name).cloned())
-        .ok_or(ApiError::MissingArgument)?;
-    let mut slice = arg_bytes.as_slice();
+/// Fetches the Borsh-encoded map of named arguments of the current call from the host.
+pub fn named_args() -> Result<RuntimeArgs, ApiError> {
+    let mut input = Vec::new();
+    host::copy_input(|size| reserve_vec_space(&mut input, size)).map_err(ApiError::Host)?;
+    if input.is_empty() {
+        return Ok(RuntimeArgs::new());
+    }
+    BorshDeserialize::try_from_slice(&input).map_err(ApiError::Io)
+}
+
+/// Decodes the named argument `name`, which has to have been passed with the type of `T` and
+/// no bytes past its value.
+///
+/// Fetches every argument from the host; use [`take_named_arg`] on the result of
+/// [`named_args`] to decode several of them.
+pub fn get_named_arg<T: BorshDeserialize + CLTyped>(name: &str) -> Result<T, ApiError> {
+    take_named_arg(&mut named_args()?, name)
+}
 
-    let deser: T = BorshDeserialize::deserialize(&mut slice).map_err(ApiError::Io)?;
-    Ok(deser)
+/// Like [`get_named_arg`], but returns `default()` if the argument was left out.
+pub fn get_named_arg_or<T: BorshDeserialize + CLTyped>(
+    name: &str,
+    default: impl FnOnce() -> T,
+) -> Result<T, ApiError> {
+    take_named_arg_or(&mut named_args()?, name, default)
+}
+
+/// Removes the argument `name` from `args` and decodes it, like [`get_named_arg`] does.
+pub fn take_named_arg<T: BorshDeserialize + CLTyped>(
+    args: &mut RuntimeArgs,
+    name: &str,
+) -> Result<T, ApiError> {
+    let arg = args.remove(name).ok_or(ApiError::MissingArgument)?;
+    let expected = T::cl_type();
+    if arg.cl_type != expected {
+        return Err(ApiError::InvalidArgumentType {
+            name: name.to_string(),
+            expected,
+            got: arg.cl_type,
+        });
+    }
+    T::try_from_slice(&arg.bytes).map_err(ApiError::Io)
+}
+
+/// Like [`take_named_arg`], but returns `default()` if the argument was left out.
+pub fn take_named_arg_or<T: BorshDeserialize + CLTyped>(
+    args: &mut RuntimeArgs,
+    name: &str,
+    default: impl FnOnce() -> T,
+) -> Result<T, ApiError> {
+    match take_named_arg(args, name) {
+        Err(ApiError::MissingArgument) => Ok(default()),
+        result => result,
+    }
 }
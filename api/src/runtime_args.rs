@@ -0,0 +1,127 @@
+use std::{collections::BTreeMap, io};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+use crate::{CLType, CLTyped};
+
+/// A Borsh-encoded argument together with its type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeArg {
+    pub cl_type: CLType,
+    pub bytes: Vec<u8>,
+}
+
+/// Encoded as the [`CLType::encode`] bytes of the type, followed by the value bytes.
+impl BorshSerialize for RuntimeArg {
+    fn serialize<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.cl_type.encode().serialize(writer)?;
+        self.bytes.serialize(writer)
+    }
+}
+
+impl BorshDeserialize for RuntimeArg {
+    fn deserialize_reader<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+        let cl_type = Vec::<u8>::deserialize_reader(reader)?;
+        let cl_type = CLType::decode(&cl_type)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, format!("{error:?}")))?;
+        let bytes = Vec::deserialize_reader(reader)?;
+        Ok(Self { cl_type, bytes })
+    }
+}
+
+/// Named arguments of a call, as passed to entry points through [`crate::named_args`].
+///
+/// Built with [`RuntimeArgs::insert`] or the [`runtime_args!`](crate::runtime_args) macro:
+///
+/// ```
+/// use api::{runtime_args, CLType};
+///
+/// let args = runtime_args! { "flag" => true, "note" => "hi".to_string() };
+/// assert_eq!(args.get("flag").unwrap().cl_type, CLType::Bool);
+/// assert_eq!(args.get("note").unwrap().bytes, borsh::to_vec("hi").unwrap());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct RuntimeArgs {
+    args: BTreeMap<String, RuntimeArg>,
+}
+
+impl RuntimeArgs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Borsh-encodes `value` as the argument `name`, replacing any previous argument of that name.
+    pub fn insert<T: BorshSerialize + CLTyped>(&mut self, name: impl Into<String>, value: T) {
+        let bytes = borsh::to_vec(&value).expect("serializing into a vec should not fail");
+        self.insert_encoded(name, T::cl_type(), bytes);
+    }
+
+    /// Adds an argument that is already Borsh-encoded.
+    pub fn insert_encoded(&mut self, name: impl Into<String>, cl_type: CLType, bytes: Vec<u8>) {
+        self.args.insert(name.into(), RuntimeArg { cl_type, bytes });
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RuntimeArg> {
+        self.args.get(name)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<RuntimeArg> {
+        self.args.remove(name)
+    }
+
+    /// Arguments ordered by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &RuntimeArg)> {
+        self.args.iter().map(|(name, arg)| (name.as_str(), arg))
+    }
+
+    pub fn len(&self) -> usize {
+        self.args.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty()
+    }
+}
+
+/// Builds [`RuntimeArgs`] from `name => value` pairs.
+#[macro_export]
+macro_rules! runtime_args {
+    () => {
+        $crate::RuntimeArgs::new()
+    };
+    ($($name:expr => $value:expr),+ $(,)?) => {{
+        let mut args = $crate::RuntimeArgs::new();
+        $(args.insert($name, $value);)+
+        args
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn borsh_round_trip() {
+        let args = runtime_args! {
+            "amount" => 5u64,
+            "recipient" => [1u8; 32],
+            "memo" => Some("gift".to_string()),
+        };
+        assert_eq!(args.len(), 3);
+        assert_eq!(args.get("amount").unwrap().cl_type, CLType::U64);
+        assert_eq!(
+            args.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            ["amount", "memo", "recipient"]
+        );
+
+        let bytes = borsh::to_vec(&args).unwrap();
+        assert_eq!(RuntimeArgs::try_from_slice(&bytes).unwrap(), args);
+        assert_eq!(borsh::to_vec(&runtime_args! {}).unwrap(), [0, 0, 0, 0]);
+
+        // Types are decoded too: count, name, type length and version precede the type tag.
+        let mut bytes = borsh::to_vec(&runtime_args! { "flag" => true }).unwrap();
+        assert_eq!(bytes[17], 0);
+        bytes[17] = 200;
+        assert!(RuntimeArgs::try_from_slice(&bytes).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+//! Helpers for exercising contracts in native tests.
+
+use std::{cell::Cell, panic, sync::Once};
+
+use crate::host;
+
+/// Why a native entry point stopped before returning.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Revert {
+    /// The entry point called [`crate::host::revert`] with this code.
+    Code(u32),
+    /// A host function exceeded the gas limit.
+    OutOfGas,
+    /// The entry point panicked with this message.
+    Panic(String),
+}
+
+/// Runs `entry_point`, turning a revert into an error instead of unwinding into the test.
+///
+/// Like a reverted call on chain, a reverted entry point leaves no changes to storage, balances,
+/// contracts or packages behind.
+///
+/// Reverts don't print the message the panic hook shows for other panics, which still do.
+///
+/// ```
+/// use api::{host, testing::{self, Revert}};
+///
+/// assert_eq!(testing::call(|| host::revert(3)), Err::<(), _>(Revert::Code(3)));
+/// assert_eq!(testing::call(|| 1 + 1), Ok(2));
+/// ```
+pub fn call<T>(entry_point: impl FnOnce() -> T) -> Result<T, Revert> {
+    quiet_reverts();
+    CALLS.with(|calls| calls.set(calls.get() + 1));
+    let result = host::transaction(entry_point);
+    CALLS.with(|calls| calls.set(calls.get() - 1));
+    result.map_err(|payload| {
+        if let Some(revert) = payload.downcast_ref::<Revert>() {
+            revert.clone()
+        } else if let Some(message) = payload.downcast_ref::<&str>() {
+            Revert::Panic(message.to_string())
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            Revert::Panic(message.clone())
+        } else {
+            Revert::Panic("non-string panic payload".to_string())
+        }
+    })
+}
+
+thread_local! {
+    /// Number of [`call`]s running on this thread.
+    static CALLS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Installs, once, a panic hook that ignores [`Revert`] payloads raised within [`call`] and hands
+/// every other panic to the hook installed before it.
+fn quiet_reverts() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let in_call = CALLS.with(|calls| calls.get() > 0);
+            if !(in_call && info.payload().is::<Revert>()) {
+                previous(info);
+            }
+        }));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_revert_payloads() {
+        assert_eq!(call(|| host::revert(7)), Err::<(), _>(Revert::Code(7)));
+        assert_eq!(
+            call(|| -> () { panic!("broken {}", 1) }),
+            Err(Revert::Panic("broken 1".to_string()))
+        );
+        assert_eq!(
+            call(|| -> () { panic!("broken") }),
+            Err(Revert::Panic("broken".to_string()))
+        );
+        assert_eq!(call(|| "done"), Ok("done"));
+    }
+}
@@ -0,0 +1,454 @@
+//! Conversion between JSON and the Borsh encoding of values, driven by their [`CLType`].
+//!
+//! Values map to JSON as follows:
+//!
+//! - `Bool`, `String` and the integer types map to the matching JSON scalar, and `Unit` to
+//!   `null`.
+//! - `ByteArray` maps to a hex string.
+//! - `Option` maps to `null` for `None` and to the inner value for `Some`. If the inner type maps
+//!   to `null` itself, as `Option` and `Unit` do, `Some` wraps it in a one-element array, so
+//!   `Option<Option<u8>>` maps to `null`, `[null]` and `[1]`.
+//! - `List` and `Tuple` map to arrays.
+//! - `Map` maps to an object if its keys are strings, and to an array of `[key, value]` pairs
+//!   otherwise.
+//!
+//! `Any` has no known layout and can't be transcoded.
+
+use serde_json::{Map, Value};
+
+use crate::{CLType, RuntimeArgs, SchemaEntryPoint};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TranscodeError {
+    /// Named arguments were not given as a JSON object.
+    NotAnObject,
+    MissingArgument(String),
+    /// Argument the entry point doesn't take.
+    UnknownArgument(String),
+    /// Value at `path` doesn't match the type `expected`.
+    InvalidValue {
+        path: String,
+        expected: CLType,
+    },
+    /// Values of this type can't be transcoded.
+    Unsupported(CLType),
+    UnexpectedEnd,
+    TrailingBytes,
+}
+
+/// Encodes the JSON object `args` into the named arguments `entry_point` takes.
+///
+/// Optional arguments missing from `args` are left out, for the entry point to fill in.
+pub fn args_to_borsh(
+    entry_point: &SchemaEntryPoint,
+    args: &Value,
+) -> Result<RuntimeArgs, TranscodeError> {
+    let args = args.as_object().ok_or(TranscodeError::NotAnObject)?;
+    if let Some(name) = args
+        .keys()
+        .find(|name| !entry_point.arguments.iter().any(|arg| arg.name == *name))
+    {
+        return Err(TranscodeError::UnknownArgument(name.clone()));
+    }
+    let mut runtime_args = RuntimeArgs::new();
+    for arg in &entry_point.arguments {
+        let value = match args.get(arg.name) {
+            Some(value) => value,
+            None if arg.optional => continue,
+            None => return Err(TranscodeError::MissingArgument(arg.name.to_string())),
+        };
+        let mut bytes = Vec::new();
+        encode(&arg.ty, value, arg.name, &mut bytes)?;
+        runtime_args.insert_encoded(arg.name, arg.ty.clone(), bytes);
+    }
+    Ok(runtime_args)
+}
+
+/// Decodes the Borsh-encoded value returned by `entry_point` into JSON.
+pub fn result_to_json(
+    entry_point: &SchemaEntryPoint,
+    bytes: &[u8],
+) -> Result<Value, TranscodeError> {
+    to_json(&entry_point.result, bytes)
+}
+
+/// Borsh-encodes the JSON `value` of type `ty`.
+pub fn to_borsh(ty: &CLType, value: &Value) -> Result<Vec<u8>, TranscodeError> {
+    let mut bytes = Vec::new();
+    encode(ty, value, "value", &mut bytes)?;
+    Ok(bytes)
+}
+
+/// Decodes the Borsh-encoded `bytes` of type `ty` into JSON, rejecting trailing bytes.
+pub fn to_json(ty: &CLType, mut bytes: &[u8]) -> Result<Value, TranscodeError> {
+    let value = decode(ty, &mut bytes, "value")?;
+    if !bytes.is_empty() {
+        return Err(TranscodeError::TrailingBytes);
+    }
+    Ok(value)
+}
+
+fn encode(
+    ty: &CLType,
+    value: &Value,
+    path: &str,
+    bytes: &mut Vec<u8>,
+) -> Result<(), TranscodeError> {
+    let invalid = || TranscodeError::InvalidValue {
+        path: path.to_string(),
+        expected: ty.clone(),
+    };
+    match ty {
+        CLType::Bool => bytes.push(value.as_bool().ok_or_else(invalid)?.into()),
+        CLType::String => encode_str(value.as_str().ok_or_else(invalid)?, bytes),
+        CLType::Unit => value.as_null().ok_or_else(invalid)?,
+        CLType::Any => return Err(TranscodeError::Unsupported(ty.clone())),
+        CLType::U8 => bytes.push(unsigned(value).ok_or_else(invalid)?),
+        CLType::U32 => {
+            bytes.extend_from_slice(&unsigned::<u32>(value).ok_or_else(invalid)?.to_le_bytes())
+        }
+        CLType::U64 => bytes.extend_from_slice(&value.as_u64().ok_or_else(invalid)?.to_le_bytes()),
+        CLType::I32 => {
+            bytes.extend_from_slice(&signed::<i32>(value).ok_or_else(invalid)?.to_le_bytes())
+        }
+        CLType::I64 => bytes.extend_from_slice(&value.as_i64().ok_or_else(invalid)?.to_le_bytes()),
+        CLType::ByteArray(len) => {
+            let array = value
+                .as_str()
+                .and_then(|hex| hex::decode(hex).ok())
+                .filter(|array| array.len() == *len as usize)
+                .ok_or_else(invalid)?;
+            bytes.extend_from_slice(&array);
+        }
+        CLType::Option(inner) => match value {
+            Value::Null => bytes.push(0),
+            value if maps_to_null(inner) => match value.as_array().map(Vec::as_slice) {
+                Some([value]) => {
+                    bytes.push(1);
+                    encode(inner, value, &format!("{path}[0]"), bytes)?;
+                }
+                _ => return Err(invalid()),
+            },
+            value => {
+                bytes.push(1);
+                encode(inner, value, path, bytes)?;
+            }
+        },
+        CLType::List(inner) => {
+            let items = value.as_array().ok_or_else(invalid)?;
+            encode_len(items.len(), bytes);
+            for (index, item) in items.iter().enumerate() {
+                encode(inner, item, &format!("{path}[{index}]"), bytes)?;
+            }
+        }
+        CLType::Map { key, value: inner } if **key == CLType::String => {
+            let entries = value.as_object().ok_or_else(invalid)?;
+            encode_len(entries.len(), bytes);
+            for (key, value) in entries {
+                encode_str(key, bytes);
+                encode(inner, value, &format!("{path}.{key}"), bytes)?;
+            }
+        }
+        CLType::Map { key, value: inner } => {
+            let entries = value.as_array().ok_or_else(invalid)?;
+            encode_len(entries.len(), bytes);
+            for (index, entry) in entries.iter().enumerate() {
+                let path = format!("{path}[{index}]");
+                match entry.as_array().map(Vec::as_slice) {
+                    Some([key_value, value]) => {
+                        encode(key, key_value, &format!("{path}[0]"), bytes)?;
+                        encode(inner, value, &format!("{path}[1]"), bytes)?;
+                    }
+                    _ => {
+                        return Err(TranscodeError::InvalidValue {
+                            path,
+                            expected: CLType::Tuple(vec![(**key).clone(), (**inner).clone()]),
+                        })
+                    }
+                }
+            }
+        }
+        CLType::Tuple(types) => {
+            let items = value
+                .as_array()
+                .filter(|items| items.len() == types.len())
+                .ok_or_else(invalid)?;
+            for (index, (ty, item)) in types.iter().zip(items).enumerate() {
+                encode(ty, item, &format!("{path}[{index}]"), bytes)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn unsigned<T: TryFrom<u64>>(value: &Value) -> Option<T> {
+    T::try_from(value.as_u64()?).ok()
+}
+
+fn signed<T: TryFrom<i64>>(value: &Value) -> Option<T> {
+    T::try_from(value.as_i64()?).ok()
+}
+
+/// Whether some value of `ty` maps to `null`.
+fn maps_to_null(ty: &CLType) -> bool {
+    matches!(ty, CLType::Option(_) | CLType::Unit)
+}
+
+fn encode_len(len: usize, bytes: &mut Vec<u8>) {
+    bytes.extend_from_slice(&(len as u32).to_le_bytes());
+}
+
+fn encode_str(value: &str, bytes: &mut Vec<u8>) {
+    encode_len(value.len(), bytes);
+    bytes.extend_from_slice(value.as_bytes());
+}
+
+fn decode(ty: &CLType, bytes: &mut &[u8], path: &str) -> Result<Value, TranscodeError> {
+    let invalid = || TranscodeError::InvalidValue {
+        path: path.to_string(),
+        expected: ty.clone(),
+    };
+    let value = match ty {
+        CLType::Bool => match take::<1>(bytes)? {
+            [0] => Value::Bool(false),
+            [1] => Value::Bool(true),
+            _ => return Err(invalid()),
+        },
+        CLType::String => Value::String(decode_str(bytes)?.ok_or_else(invalid)?),
+        CLType::Unit => Value::Null,
+        CLType::Any => return Err(TranscodeError::Unsupported(ty.clone())),
+        CLType::U8 => take::<1>(bytes)?[0].into(),
+        CLType::U32 => u32::from_le_bytes(take(bytes)?).into(),
+        CLType::U64 => u64::from_le_bytes(take(bytes)?).into(),
+        CLType::I32 => i32::from_le_bytes(take(bytes)?).into(),
+        CLType::I64 => i64::from_le_bytes(take(bytes)?).into(),
+        CLType::ByteArray(len) => Value::String(hex::encode(take_slice(bytes, *len as usize)?)),
+        CLType::Option(inner) => match take::<1>(bytes)? {
+            [0] => Value::Null,
+            [1] if maps_to_null(inner) => {
+                Value::Array(vec![decode(inner, bytes, &format!("{path}[0]"))?])
+            }
+            [1] => decode(inner, bytes, path)?,
+            _ => return Err(invalid()),
+        },
+        CLType::List(inner) => {
+            let len = decode_len(bytes)?;
+            let items = (0..len)
+                .map(|index| decode(inner, bytes, &format!("{path}[{index}]")))
+                .collect::<Result<_, _>>()?;
+            Value::Array(items)
+        }
+        CLType::Map { key, value: inner } if **key == CLType::String => {
+            let len = decode_len(bytes)?;
+            let mut entries = Map::new();
+            for _ in 0..len {
+                let key = decode_str(bytes)?.ok_or_else(invalid)?;
+                let value = decode(inner, bytes, &format!("{path}.{key}"))?;
+                entries.insert(key, value);
+            }
+            Value::Object(entries)
+        }
+        CLType::Map { key, value: inner } => {
+            let len = decode_len(bytes)?;
+            let entries = (0..len)
+                .map(|index| {
+                    let path = format!("{path}[{index}]");
+                    let key = decode(key, bytes, &format!("{path}[0]"))?;
+                    let value = decode(inner, bytes, &format!("{path}[1]"))?;
+                    Ok(Value::Array(vec![key, value]))
+                })
+                .collect::<Result<_, _>>()?;
+            Value::Array(entries)
+        }
+        CLType::Tuple(types) => {
+            let items = types
+                .iter()
+                .enumerate()
+                .map(|(index, ty)| decode(ty, bytes, &format!("{path}[{index}]")))
+                .collect::<Result<_, _>>()?;
+            Value::Array(items)
+        }
+    };
+    Ok(value)
+}
+
+fn take_slice<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], TranscodeError> {
+    if bytes.len() < len {
+        return Err(TranscodeError::UnexpectedEnd);
+    }
+    let (value, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(value)
+}
+
+fn take<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N], TranscodeError> {
+    Ok(take_slice(bytes, N)?.try_into().unwrap())
+}
+
+fn decode_len(bytes: &mut &[u8]) -> Result<u32, TranscodeError> {
+    Ok(u32::from_le_bytes(take(bytes)?))
+}
+
+/// Decodes a string, returning `None` if it isn't valid UTF-8.
+fn decode_str(bytes: &mut &[u8]) -> Result<Option<String>, TranscodeError> {
+    let len = decode_len(bytes)?;
+    let value = take_slice(bytes, len as usize)?;
+    Ok(String::from_utf8(value.to_vec()).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{CLTyped, SchemaArgument};
+
+    fn round_trip<T: CLTyped + borsh::BorshSerialize>(value: T, json: Value) {
+        let bytes = borsh::to_vec(&value).unwrap();
+        assert_eq!(to_borsh(&T::cl_type(), &json), Ok(bytes.clone()));
+        assert_eq!(to_json(&T::cl_type(), &bytes), Ok(json));
+    }
+
+    #[test]
+    fn matches_borsh() {
+        round_trip(true, json!(true));
+        round_trip("hi".to_string(), json!("hi"));
+        round_trip((), json!(null));
+        round_trip(200u8, json!(200));
+        round_trip(u32::MAX, json!(u32::MAX));
+        round_trip(u64::MAX, json!(u64::MAX));
+        round_trip(-5i32, json!(-5));
+        round_trip(i64::MIN, json!(i64::MIN));
+        round_trip([0xab_u8; 4], json!("abababab"));
+        round_trip(Some(3u64), json!(3));
+        round_trip(None::<u64>, json!(null));
+        round_trip(None::<Option<u64>>, json!(null));
+        round_trip(Some(None::<u64>), json!([null]));
+        round_trip(Some(Some(3u64)), json!([3]));
+        round_trip(Some(()), json!([null]));
+        round_trip(vec![vec![1u8], vec![]], json!([[1], []]));
+        round_trip((1u32, "a".to_string()), json!([1, "a"]));
+        round_trip(
+            BTreeMap::from([("b".to_string(), 2u64), ("a".to_string(), 1)]),
+            json!({"a": 1, "b": 2}),
+        );
+        round_trip(BTreeMap::from([(7u8, true)]), json!([[7, true]]));
+    }
+
+    #[test]
+    fn validates_against_type() {
+        assert_eq!(
+            to_borsh(&CLType::List(Box::new(CLType::U8)), &json!([1, 256])),
+            Err(TranscodeError::InvalidValue {
+                path: "value[1]".to_string(),
+                expected: CLType::U8,
+            })
+        );
+        assert_eq!(
+            to_borsh(&CLType::ByteArray(2), &json!("abababab")),
+            Err(TranscodeError::InvalidValue {
+                path: "value".to_string(),
+                expected: CLType::ByteArray(2),
+            })
+        );
+        assert_eq!(
+            to_borsh(&<Option<Option<u8>>>::cl_type(), &json!(1)),
+            Err(TranscodeError::InvalidValue {
+                path: "value".to_string(),
+                expected: <Option<Option<u8>>>::cl_type(),
+            })
+        );
+        assert_eq!(
+            to_borsh(&CLType::Any, &json!(1)),
+            Err(TranscodeError::Unsupported(CLType::Any))
+        );
+        assert_eq!(
+            to_json(&CLType::Bool, &[2]),
+            Err(TranscodeError::InvalidValue {
+                path: "value".to_string(),
+                expected: CLType::Bool,
+            })
+        );
+        assert_eq!(
+            to_json(&CLType::U32, &[1]),
+            Err(TranscodeError::UnexpectedEnd)
+        );
+        assert_eq!(
+            to_json(&CLType::U8, &[1, 2]),
+            Err(TranscodeError::TrailingBytes)
+        );
+    }
+
+    #[test]
+    fn transcodes_entry_point_args_and_result() {
+        let entry_point = SchemaEntryPoint {
+            name: "flip",
+            arguments: vec![
+                SchemaArgument {
+                    name: "flag",
+                    ty: CLType::Bool,
+                    optional: false,
+                    default: None,
+                },
+                SchemaArgument {
+                    name: "note",
+                    ty: CLType::String,
+                    optional: false,
+                    default: None,
+                },
+                SchemaArgument {
+                    name: "count",
+                    ty: CLType::U32,
+                    optional: true,
+                    default: Some("1"),
+                },
+            ],
+            result: CLType::Option(Box::new(CLType::U64)),
+            payable: false,
+        };
+
+        assert_eq!(
+            args_to_borsh(&entry_point, &json!({"flag": true, "note": "hi"})),
+            Ok(crate::runtime_args! { "flag" => true, "note" => "hi".to_string() })
+        );
+        assert_eq!(
+            args_to_borsh(
+                &entry_point,
+                &json!({"flag": true, "note": "hi", "count": 3})
+            ),
+            Ok(crate::runtime_args! {
+                "flag" => true,
+                "note" => "hi".to_string(),
+                "count" => 3u32,
+            })
+        );
+
+        assert_eq!(
+            args_to_borsh(&entry_point, &json!({"flag": true})),
+            Err(TranscodeError::MissingArgument("note".to_string()))
+        );
+        assert_eq!(
+            args_to_borsh(
+                &entry_point,
+                &json!({"flag": true, "note": "hi", "other": 1})
+            ),
+            Err(TranscodeError::UnknownArgument("other".to_string()))
+        );
+        assert_eq!(
+            args_to_borsh(&entry_point, &json!({"flag": "yes", "note": "hi"})),
+            Err(TranscodeError::InvalidValue {
+                path: "flag".to_string(),
+                expected: CLType::Bool,
+            })
+        );
+        assert_eq!(
+            args_to_borsh(&entry_point, &json!([])),
+            Err(TranscodeError::NotAnObject)
+        );
+
+        let result = borsh::to_vec(&Some(5u64)).unwrap();
+        assert_eq!(result_to_json(&entry_point, &result), Ok(json!(5)));
+    }
+}
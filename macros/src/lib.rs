@@ -1,16 +1,193 @@
 extern crate proc_macro;
-use std::str::FromStr;
 
-use api::{get_named_arg, EntryPoint};
-use paste::paste;
-use proc_macro::{Ident, TokenStream, TokenTree};
+use proc_macro::TokenStream;
 use quote::{format_ident, quote};
 use syn::{
-    parse_macro_input,
-    token::{Crate, Pub},
-    Data, DeriveInput, Item, ItemFn, ItemImpl, Type, VisRestricted, Visibility,
+    parse_macro_input, punctuated::Punctuated, token::Comma, Data, DeriveInput, FnArg, ItemFn,
+    ItemImpl, ReturnType, Type,
 };
 
+/// Generates statements decoding every named argument from the call input, and the expressions
+/// passing the decoded values to the function. Receivers are skipped.
+///
+/// The input is fetched from the host and decoded once, then each argument is taken out of it.
+///
+/// Arguments left out of the input are `None` if they are `Option`s, or the value `default_of`
+/// gives for them.
+fn decode_args<'a>(
+    inputs: &Punctuated<FnArg, Comma>,
+    default_of: impl Fn(&syn::Ident) -> Option<&'a syn::Expr>,
+) -> (Vec<proc_macro2::TokenStream>, Vec<proc_macro2::TokenStream>) {
+    let mut handle_args = Vec::new();
+    let mut arg_calls = Vec::new();
+
+    if inputs
+        .iter()
+        .any(|input| matches!(input, FnArg::Typed(_)))
+    {
+        handle_args.push(quote! {
+            let mut __named_args = api::named_args().expect("should get named args");
+        });
+    }
+
+    for input in inputs {
+        let typed = match input {
+            FnArg::Receiver(_) => continue,
+            FnArg::Typed(typed) => typed,
+        };
+        let name = match typed.pat.as_ref() {
+            syn::Pat::Ident(ident) => &ident.ident,
+            _ => todo!(),
+        };
+
+        let get = match default_of(name) {
+            Some(default) => {
+                quote! { api::take_named_arg_or(&mut __named_args, stringify!(#name), || #default) }
+            }
+            None if is_option(&typed.ty) => {
+                quote! { api::take_named_arg_or(&mut __named_args, stringify!(#name), || None) }
+            }
+            None => quote! { api::take_named_arg(&mut __named_args, stringify!(#name)) },
+        };
+
+        let ty = owned_type(&typed.ty);
+        handle_args.push(quote! {
+            let #name: #ty = #get.expect("should get named arg");
+        });
+        // Borrowed arguments are decoded into their owned counterpart and lent to the
+        // function.
+        match typed.ty.as_ref() {
+            Type::Reference(_) => arg_calls.push(quote! {
+                api::__private::Borrow::borrow(&#name)
+            }),
+            _ => arg_calls.push(quote! {
+                #name
+            }),
+        }
+    }
+
+    (handle_args, arg_calls)
+}
+
+/// Type an argument declared as `ty` is passed as: the owned counterpart of borrowed types.
+fn owned_type(ty: &Type) -> proc_macro2::TokenStream {
+    match ty {
+        Type::Reference(reference) => {
+            let elem = &reference.elem;
+            quote! { <#elem as api::__private::ToOwned>::Owned }
+        }
+        ty => quote! { #ty },
+    }
+}
+
+/// Whether `ty` is spelled as an `Option`, or a reference to one.
+///
+/// Macros can't resolve types, so an `Option` under another name, like a type alias, is not
+/// recognized; such parameters need `#[casper(default = None)]` to be optional.
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "Option"),
+        Type::Reference(reference) => is_option(&reference.elem),
+        Type::Paren(paren) => is_option(&paren.elem),
+        Type::Group(group) => is_option(&group.elem),
+        _ => false,
+    }
+}
+
+/// Builds the `api::SchemaArgument` describing the parameter `name` of type `ty`.
+fn schema_argument(
+    name: &syn::Ident,
+    ty: &Type,
+    default: Option<&syn::Expr>,
+) -> proc_macro2::TokenStream {
+    let optional = default.is_some() || is_option(ty);
+    let ty = owned_type(ty);
+    let default = match default {
+        Some(default) => quote! { Some(stringify!(#default)) },
+        None => quote! { None },
+    };
+    quote! {
+        api::SchemaArgument {
+            name: stringify!(#name),
+            ty: {
+                use api::CLTyped;
+                <#ty>::cl_type()
+            },
+            optional: #optional,
+            default: #default,
+        }
+    }
+}
+
+/// Removes `#[casper(default = expr)]` from `attrs`, returning `expr`.
+fn take_casper_default(attrs: &mut Vec<syn::Attribute>) -> syn::Result<Option<syn::Expr>> {
+    let mut default = None;
+    let mut error = None;
+    attrs.retain(|attr| {
+        if !attr.path().is_ident("casper") {
+            return true;
+        }
+        match attr.parse_args::<syn::MetaNameValue>() {
+            Ok(meta) if meta.path.is_ident("default") => {
+                default = Some(meta.value);
+                false
+            }
+            Ok(_) => true,
+            Err(err) => {
+                error = Some(err);
+                true
+            }
+        }
+    });
+    match error {
+        Some(error) => Err(error),
+        None => Ok(default),
+    }
+}
+
+/// Removes `#[casper(flag)]` from `attrs`, returning whether it was present.
+fn take_casper_flag(attrs: &mut Vec<syn::Attribute>, flag: &str) -> bool {
+    let len = attrs.len();
+    attrs.retain(|attr| {
+        !(attr.path().is_ident("casper")
+            && attr
+                .parse_args::<syn::Ident>()
+                .is_ok_and(|ident| ident == flag))
+    });
+    attrs.len() != len
+}
+
+/// Symbols wasm modules built with `api` already define: its allocator exports, and the memory
+/// functions of the compiler runtime.
+const RESERVED_EXPORTS: &[&str] = &[
+    "alloc", "dealloc", "memcpy", "memmove", "memset", "memcmp", "bcmp",
+];
+
+/// Rejects exports whose name would clash with a symbol in [`RESERVED_EXPORTS`] when linking.
+fn check_export_name(name: &syn::Ident) -> syn::Result<()> {
+    if RESERVED_EXPORTS.iter().any(|reserved| name == reserved) {
+        return Err(syn::Error::new(
+            name.span(),
+            format!("`{name}` is already exported by every contract module; rename it"),
+        ));
+    }
+    Ok(())
+}
+
+/// Generates code handing `_result` back to the caller, unless the function returns nothing.
+fn return_result(output: &ReturnType) -> proc_macro2::TokenStream {
+    match output {
+        ReturnType::Default => quote! {},
+        ReturnType::Type(..) => quote! {
+            api::ret(&_result).expect("should return value");
+        },
+    }
+}
+
 #[proc_macro_derive(Contract)]
 pub fn derive_casper_contract(input: TokenStream) -> TokenStream {
     // Parse the input tokens into a syntax tree
@@ -19,7 +196,6 @@ pub fn derive_casper_contract(input: TokenStream) -> TokenStream {
                 dbg!(&contract);
                 // todo!("{contract:?}")
                 let name = &contract.ident;
-                let vis = &contract.vis;
 
                 let data_struct = match &contract.data {
                     Data::Struct(s) => s,
@@ -55,7 +231,7 @@ pub fn derive_casper_contract(input: TokenStream) -> TokenStream {
                     })
                 }
 
-                return quote! {
+                quote! {
                     // #vis struct #name {
                     //     #(#fields,)*
                     // }
@@ -77,6 +253,22 @@ pub fn derive_casper_contract(input: TokenStream) -> TokenStream {
                             // }
                         }
 
+                        fn install() -> Result<api::host::Address, api::host::Error> {
+                            Self::__casper_with_entry_points(api::host::create_contract)
+                        }
+
+                        fn install_version(
+                            package: &api::host::Address,
+                        ) -> Result<api::host::ContractVersion, api::host::Error> {
+                            Self::__casper_with_entry_points(|entry_points| {
+                                api::host::add_contract_version(package, entry_points)
+                            })
+                        }
+
+                        fn register() {
+                            Self::__casper_register()
+                        }
+
 
                     }
 
@@ -89,7 +281,7 @@ pub fn derive_casper_contract(input: TokenStream) -> TokenStream {
                         }
                     }
                 }
-                .into();
+                .into()
 }
 
 
@@ -97,13 +289,56 @@ pub fn derive_casper_contract(input: TokenStream) -> TokenStream {
 pub fn casper(attrs: TokenStream, item: TokenStream) -> TokenStream {
     // eprintln!("{attrs:?}");
 
-    let mut attrs_iter = attrs.into_iter().peekable();
+    let attrs = parse_macro_input!(attrs with Punctuated::<syn::Ident, Comma>::parse_terminated);
+    let mut attrs = attrs.into_iter();
+    let kind = attrs.next();
+    // `#[casper(entry_points, no_export)]` keeps the public methods out of the wasm exports.
+    let mut export_methods = true;
+    for flag in attrs {
+        match &kind {
+            Some(kind) if kind == "entry_points" && flag == "no_export" => export_methods = false,
+            _ => {
+                return syn::Error::new(flag.span(), format!("unknown `#[casper]` flag `{flag}`"))
+                    .to_compile_error()
+                    .into()
+            }
+        }
+    }
 
-    for attr in &mut attrs_iter {
-        let item = item.clone();
+    if let Some(attr) = kind {
         match attr {
-            proc_macro::TokenTree::Ident(ident) if ident.to_string() == "entry_points" => {
-                let entry_points = parse_macro_input!(item as ItemImpl);
+            ident if ident == "entry_points" => {
+                let mut entry_points = parse_macro_input!(item as ItemImpl);
+
+                // `#[casper(..)]` attributes of methods and their parameters are consumed here
+                // and not emitted.
+                let mut payable_entry_points = Vec::new();
+                let mut defaults = Vec::new();
+                for entry_point in &mut entry_points.items {
+                    if let syn::ImplItem::Fn(func) = entry_point {
+                        if take_casper_flag(&mut func.attrs, "payable") {
+                            payable_entry_points.push(func.sig.ident.clone());
+                        }
+                        for input in &mut func.sig.inputs {
+                            let FnArg::Typed(typed) = input else { continue };
+                            let default = match take_casper_default(&mut typed.attrs) {
+                                Ok(default) => default,
+                                Err(error) => return error.to_compile_error().into(),
+                            };
+                            if let (Some(default), syn::Pat::Ident(name)) =
+                                (default, typed.pat.as_ref())
+                            {
+                                defaults.push((func.sig.ident.clone(), name.ident.clone(), default));
+                            }
+                        }
+                    }
+                }
+                let default_of = |func_name: &syn::Ident, name: &syn::Ident| {
+                    defaults
+                        .iter()
+                        .find(|(func, arg, _)| func == func_name && arg == name)
+                        .map(|(_, _, default)| default)
+                };
 
                 let name = match entry_points.self_ty.as_ref() {
 
@@ -117,6 +352,11 @@ pub fn casper(attrs: TokenStream, item: TokenStream) -> TokenStream {
                 // }
 
                 let mut defs = Vec::new();
+                let mut thunks = Vec::new();
+                let mut params = Vec::new();
+                let mut descriptors = Vec::new();
+                let mut registrations = Vec::new();
+                let mut exports = Vec::new();
 
                 let mut names = Vec::new();
                 for entry_point in &entry_points.items {
@@ -163,28 +403,136 @@ pub fn casper(attrs: TokenStream, item: TokenStream) -> TokenStream {
                             syn::Pat::Wild(_) => todo!("Wild"),
                             _ => todo!(),
                         };
-                        let ty = &typed.ty;
-                        args.push(quote! {
-                            api::SchemaArgument {
-                                name: stringify!(#name),
-                                ty: {
-                                    use api::CLTyped;
-                                    <#ty>::cl_type()
-                                },
+                        let default = default_of(func_name, &name.ident);
+                        args.push(schema_argument(&name.ident, &typed.ty, default));
+
+
+                    }
+
+
+                    let thunk_name = format_ident!("__casper_entry_point_{func_name}");
+                    let (handle_args, arg_calls) =
+                        decode_args(&func.sig.inputs, |name| default_of(func_name, name));
+                    let handle_result = return_result(&func.sig.output);
+                    let call = match func.sig.receiver() {
+                        Some(_) => quote! {
+                            #[allow(unused_mut)]
+                            let mut contract = <Self as api::Contract>::new();
+                            let _result = contract.#func_name(#(#arg_calls,)*);
+                        },
+                        None => quote! {
+                            let _result = Self::#func_name(#(#arg_calls,)*);
+                        },
+                    };
+                    let payable = payable_entry_points.contains(func_name);
+                    let check_payable = if payable {
+                        quote! {}
+                    } else {
+                        quote! {
+                            if api::host::attached_value() > 0 {
+                                api::host::revert(api::REVERT_NOT_PAYABLE);
+                            }
+                        }
+                    };
+                    let thunk_body = quote! {
+                        #check_payable
+                        #(#handle_args)*
+                        #call
+                        #handle_result
+                    };
+                    if let (true, syn::Visibility::Public(_)) = (export_methods, &func.vis) {
+                        if let Err(error) = check_export_name(func_name) {
+                            return error.to_compile_error().into();
+                        }
+                        // Each call starts on a fresh instance, so the export registers the entry
+                        // points before dispatching to its own.
+                        exports.push(quote! {
+                            #[cfg(target_arch = "wasm32")]
+                            #[no_mangle]
+                            pub extern "C" fn #func_name() {
+                                <#name as api::Contract>::register();
+                                api::dispatch(stringify!(#func_name))
+                                    .expect("entry point should be registered");
                             }
                         });
+                    }
+                    registrations.push(quote! {
+                        // The thunk decodes its arguments from the host itself.
+                        unsafe { api::register_func(stringify!(#func_name), Self::#thunk_name) };
+                    });
+                    thunks.push(quote! {
+                        #[doc(hidden)]
+                        #[cfg(target_arch = "wasm32")]
+                        extern "C" fn #thunk_name() {
+                            #thunk_body
+                        }
 
+                        #[doc(hidden)]
+                        #[cfg(not(target_arch = "wasm32"))]
+                        fn #thunk_name() {
+                            #thunk_body
+                        }
+                    });
 
+                    let params_name = format_ident!("{func_name}_params");
+                    let types_name = format_ident!("{func_name}_types");
+                    let mut entry_point_params = Vec::new();
+                    let mut entry_point_types = Vec::new();
+                    for input in &func.sig.inputs {
+                        let typed = match input {
+                            syn::FnArg::Receiver(_) => continue,
+                            syn::FnArg::Typed(typed) => typed,
+                        };
+                        let name = match typed.pat.as_ref() {
+                            syn::Pat::Ident(ident) => &ident.ident,
+                            _ => todo!(),
+                        };
+                        let ty = owned_type(&typed.ty);
+                        let index = entry_point_types.len();
+                        entry_point_types.push(quote! {
+                            <#ty>::cl_type().encode()
+                        });
+                        entry_point_params.push(quote! {
+                            api::host::Param {
+                                name_ptr: stringify!(#name).as_ptr(),
+                                name_len: stringify!(#name).len(),
+                                ty_ptr: #types_name[#index].as_ptr(),
+                                ty_len: #types_name[#index].len(),
+                            }
+                        });
                     }
-
+                    params.push(quote! {
+                        let #types_name: Vec<Vec<u8>> = vec![ #(#entry_point_types,)* ];
+                        let #params_name: &[api::host::Param] = &[ #(#entry_point_params,)* ];
+                    });
+                    descriptors.push(quote! {
+                        api::host::EntryPoint {
+                            name_ptr: stringify!(#func_name).as_ptr(),
+                            name_len: stringify!(#func_name).len(),
+                            params_ptr: #params_name.as_ptr(),
+                            params_size: #params_name.len(),
+                            fptr: Self::#thunk_name as *const core::ffi::c_void,
+                        }
+                    });
 
                     // let mut args = Vec::new();
                     // for arg in &entry_point
 
+                    let result = match &func.sig.output {
+                        ReturnType::Default => quote! { api::CLType::Unit },
+                        ReturnType::Type(_, ty) => quote! {
+                            {
+                                use api::CLTyped;
+                                <#ty>::cl_type()
+                            }
+                        },
+                    };
                     defs.push(quote! {
                         api::SchemaEntryPoint {
                             name: stringify!(#func_name),
-                            arguments: vec![ #(#args,)* ]
+                            arguments: vec![ #(#args,)* ],
+                            result: #result,
+                            payable: #payable,
                         }
                     });
                 }
@@ -206,55 +554,76 @@ pub fn casper(attrs: TokenStream, item: TokenStream) -> TokenStream {
                                 entry_points,
                             }
                         }
+
+                        #(#thunks)*
+
+                        /// Registers a dispatcher for every entry point, under its name.
+                        #[doc(hidden)]
+                        fn __casper_register() {
+                            #(#registrations)*
+                        }
+
+                        /// Passes descriptors of all entry points to `f`.
+                        #[doc(hidden)]
+                        fn __casper_with_entry_points<R>(
+                            f: impl FnOnce(&[api::host::EntryPoint]) -> R,
+                        ) -> R {
+                            use api::CLTyped;
+                            #(#params)*
+                            let entry_points = [
+                                #(#descriptors,)*
+                            ];
+                            f(&entry_points)
+                        }
                     }
+
+                    // Unless the impl is marked `no_export`, public entry points are also exported
+                    // from wasm modules under their name, which has to be unique among the exports
+                    // of the crate.
+                    #(#exports)*
                 };
                 eprintln!("{res}");
                 return res.into();
             }
-            proc_macro::TokenTree::Ident(ident) if ident.to_string() == "contract" => {
+            ident if ident == "payable" => {
+                return syn::Error::new(
+                    ident.span(),
+                    "`#[casper(payable)]` is only valid on methods of `#[casper(entry_points)]`",
+                )
+                .to_compile_error()
+                .into();
+            }
+            ident if ident == "contract" => {
 
                 todo!()
             }
-            proc_macro::TokenTree::Ident(ident) if ident.to_string() == "export" => {
-                let func = parse_macro_input!(item as ItemFn);
-                let func_name = &func.sig.ident;
-
-                let mut arg_slices = Vec::new();
-                let mut arg_casts = Vec::new();
-                let mut arg_calls = Vec::new();
-
-                for input in &func.sig.inputs {
-                    let name = match input {
-                        syn::FnArg::Receiver(receiver) => {
-                            todo!("{receiver:?}")
-                        },
-                        syn::FnArg::Typed(typed) => {
-                            match typed.pat.as_ref() {
-                                syn::Pat::Ident(ident) => &ident.ident,
-                                _ => todo!(),
-                            }
-                        }
+            ident if ident == "export" => {
+                let mut func = parse_macro_input!(item as ItemFn);
+
+                // Defaults are consumed here, like those of `#[casper(entry_points)]` methods.
+                let mut defaults = Vec::new();
+                for input in &mut func.sig.inputs {
+                    let FnArg::Typed(typed) = input else { continue };
+                    let default = match take_casper_default(&mut typed.attrs) {
+                        Ok(default) => default,
+                        Err(error) => return error.to_compile_error().into(),
                     };
-                    // let name = match typed.pat.as_ref() {
-                    //     syn::Pat::Ident(ident) => &ident.ident,
-                    //     _ => todo!(),
-                    // };
-
-                    // let name = input.n
-                    let arg = quote! {
-                        unsafe { core::ptr::NonNull::new_unchecked(#name).as_ref() }.as_slice()
-                    };
-
-                    arg_casts.push(arg);
-                    let arg_slice = quote! {
-                        #name: *mut api::host::Slice
-                    };
-                    arg_slices.push(arg_slice);
-
-                    arg_calls.push(quote! {
-                        name
-                    })
+                    if let (Some(default), syn::Pat::Ident(name)) = (default, typed.pat.as_ref()) {
+                        defaults.push((name.ident.clone(), default));
+                    }
                 }
+                let func_name = func.sig.ident.clone();
+                if let Err(error) = check_export_name(&func_name) {
+                    return error.to_compile_error().into();
+                }
+                let (handle_args, arg_calls) = decode_args(&func.sig.inputs, |name| {
+                    defaults
+                        .iter()
+                        .find(|(arg, _)| arg == name)
+                        .map(|(_, default)| default)
+                });
+
+                let handle_result = return_result(&func.sig.output);
 
                 // Ident::
                 let mod_name = format_ident!("__casper__export_{func_name}");
@@ -268,8 +637,10 @@ pub fn casper(attrs: TokenStream, item: TokenStream) -> TokenStream {
 
                     #[cfg(target_arch = "wasm32")]
                     #[no_mangle]
-                    pub extern "C" fn #func_name( #(#arg_slices,)* ) {
-                        #mod_name::#func_name(#(#arg_casts,)*);
+                    pub extern "C" fn #func_name() {
+                        #(#handle_args)*
+                        let _result = #mod_name::#func_name(#(#arg_calls,)*);
+                        #handle_result
                     }
 
                     #[cfg(not(target_arch = "wasm32"))]
@@ -310,40 +681,51 @@ pub fn casper(attrs: TokenStream, item: TokenStream) -> TokenStream {
 }
 
 #[proc_macro_attribute]
-pub fn entry_point(attr: TokenStream, item: TokenStream) -> TokenStream {
-    let func = parse_macro_input!(item as ItemFn);
-
+pub fn entry_point(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut func = parse_macro_input!(item as ItemFn);
+
+    // Defaults are consumed here, like those of `#[casper(entry_points)]` methods.
+    let mut defaults = Vec::new();
+    for input in &mut func.sig.inputs {
+        let FnArg::Typed(typed) = input else { todo!() };
+        let default = match take_casper_default(&mut typed.attrs) {
+            Ok(default) => default,
+            Err(error) => return error.to_compile_error().into(),
+        };
+        if let (Some(default), syn::Pat::Ident(name)) = (default, typed.pat.as_ref()) {
+            defaults.push((name.ident.clone(), default));
+        }
+    }
     let vis = &func.vis;
     let _sig = &func.sig;
     let func_name = &func.sig.ident;
 
     let block = &func.block;
 
-    let mut handle_args = Vec::new();
-    let mut params = Vec::new();
+    let default_of = |name: &syn::Ident| {
+        defaults
+            .iter()
+            .find(|(arg, _)| arg == name)
+            .map(|(_, default)| default)
+    };
 
-    for arg in &func.sig.inputs {
-        let typed = match arg {
-            syn::FnArg::Receiver(_) => todo!(),
-            syn::FnArg::Typed(typed) => typed,
-        };
+    let (mut handle_args, arg_calls) = decode_args(&func.sig.inputs, default_of);
+    let mut params = Vec::new();
 
+    for (arg, call) in func.sig.inputs.iter().zip(&arg_calls) {
+        let FnArg::Typed(typed) = arg else { todo!() };
         let name = match typed.pat.as_ref() {
             syn::Pat::Ident(ident) => &ident.ident,
             _ => todo!(),
         };
 
+        // The block sees every argument with the type it was declared with.
         let ty = &typed.ty;
+        handle_args.push(quote! {
+            let #name: #ty = #call;
+        });
 
-        let tok = quote! {
-            let #typed = api::get_named_arg(stringify!(#name)).expect("should get named arg");
-        };
-        handle_args.push(tok);
-
-        let tok2 = quote! {
-            (stringify!(#name), <#ty>::cl_type())
-        };
-        params.push(tok2);
+        params.push(schema_argument(name, ty, default_of(name)));
     }
 
     // let len = params.len();
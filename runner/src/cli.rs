@@ -0,0 +1,369 @@
+//! Command-line tool for a contract crate, run from its native `main`: prints the schema of the
+//! contract, builds it to wasm with the schema embedded, and calls its entry points on a local
+//! state file.
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, ExitCode},
+};
+
+use api::{
+    host::{self, Address, Context, StateFormat, StorageEntry, TestEnv},
+    testing, transcode, Contract,
+};
+use serde_json::json;
+
+/// Name of the custom section holding the schema JSON of a contract built with `build`.
+pub const SCHEMA_SECTION: &str = "casper_schema";
+
+const USAGE: &str = "\
+usage:
+    schema                              print the schema of the contract as JSON
+    build                               build the contract to wasm, embedding its schema
+    wasm-schema <wasm>                  print the schema embedded in a built contract
+    call <state> <entry point> [args]   call an entry point on a state file
+
+`args` is a JSON object mapping argument names to their values. The state file, holding the
+address of the contract and its storage, is created if missing, and saved as JSON if its name
+ends in `.json`, or as Borsh.
+
+The report of a call holds the returned value, the revert with its code, e.g. `Code(1)`, the
+messages printed, which stand in for events until the host has them, and the storage entries
+added, changed and removed.";
+
+const WASM_HEADER: &[u8] = b"\0asm\x01\0\0\0";
+const CUSTOM_SECTION_ID: u8 = 0;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Crate the contract is defined in, as seen by cargo.
+pub struct Package {
+    pub manifest_dir: &'static str,
+    pub name: &'static str,
+}
+
+/// Runs the command given on the command line for contract `C`.
+pub fn main<C: Contract>(package: Package) -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["schema"] => schema_json::<C>().map(|schema| println!("{schema}")),
+        ["build"] => build::<C>(&package).map(|path| println!("{}", path.display())),
+        ["wasm-schema", wasm] => wasm_schema(wasm).map(|schema| println!("{schema}")),
+        ["call", state, entry_point] => call::<C>(state, entry_point, "{}"),
+        ["call", state, entry_point, args] => call::<C>(state, entry_point, args),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn schema_json<C: Contract>() -> Result<String> {
+    Ok(serde_json::to_string_pretty(&C::schema())?)
+}
+
+fn cargo() -> Command {
+    Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+}
+
+/// Builds the package for wasm and embeds the schema into the module, returning its path.
+fn build<C: Contract>(package: &Package) -> Result<PathBuf> {
+    let status = cargo()
+        .args(["build", "--release", "--target", "wasm32-unknown-unknown"])
+        .current_dir(package.manifest_dir)
+        .status()?;
+    if !status.success() {
+        return Err("cargo build failed".into());
+    }
+
+    let metadata = cargo()
+        .args(["metadata", "--format-version", "1", "--no-deps"])
+        .current_dir(package.manifest_dir)
+        .output()?;
+    if !metadata.status.success() {
+        return Err(format!(
+            "cargo metadata failed: {}",
+            String::from_utf8_lossy(&metadata.stderr).trim()
+        )
+        .into());
+    }
+    let metadata: serde_json::Value = serde_json::from_slice(&metadata.stdout)?;
+    let target_dir = metadata["target_directory"]
+        .as_str()
+        .ok_or("cargo metadata has no target directory")?;
+    let path = Path::new(target_dir)
+        .join("wasm32-unknown-unknown/release")
+        .join(format!("{}.wasm", package.name));
+
+    let wasm = embed_schema(&fs::read(&path)?, schema_json::<C>()?.as_bytes())?;
+    fs::write(&path, wasm)?;
+    Ok(path)
+}
+
+fn wasm_schema(path: &str) -> Result<String> {
+    let wasm = fs::read(path)?;
+    let schema = extract_schema(&wasm)?.ok_or("module has no embedded schema")?;
+    Ok(String::from_utf8(schema.to_vec())?)
+}
+
+/// Calls `entry_point` of `C`, whose address and storage are kept in the state file.
+///
+/// The first call installs the contract. Later calls put its entry points back at the saved
+/// address without installing it again.
+fn call<C: Contract>(state: &str, entry_point: &str, args: &str) -> Result<()> {
+    let format = StateFormat::from_path(state);
+    let (env, address) = if Path::new(state).exists() {
+        let env = TestEnv::load(state, format)?;
+        let address = env
+            .context()
+            .contract_address
+            .ok_or("state file has no contract address")?;
+        reinstall::<C>(&env, address)?;
+        (env, address)
+    } else {
+        let env = TestEnv::new();
+        let address = env
+            .deploy::<C>()
+            .map_err(|error| format!("failed to install contract: {error:?}"))?;
+        (env, address)
+    };
+
+    let schema = C::schema();
+    let schema_entry_point = schema
+        .entry_points
+        .iter()
+        .find(|schema_entry_point| schema_entry_point.name == entry_point)
+        .ok_or_else(|| format!("contract has no entry point `{entry_point}`"))?;
+    let args = transcode::args_to_borsh(schema_entry_point, &serde_json::from_str(args)?)
+        .map_err(|error| format!("invalid arguments: {error:?}"))?;
+    let export = env
+        .entry_point(address, entry_point)
+        .ok_or_else(|| format!("contract has no entry point `{entry_point}`"))?;
+
+    let storage_before = env.storage();
+    let printed_before = env.printed().len();
+    // Run through the test helpers rather than `host::call`, which only reports that the
+    // entry point reverted, not its code.
+    env.set_context(Context {
+        contract_address: Some(address),
+        ..env.context()
+    });
+    env.set_args(args);
+    let result = env.run(|| {
+        testing::call(|| {
+            export();
+            host::take_return_data()
+        })
+    });
+
+    let returned = match &result {
+        Ok(output) => Some(
+            transcode::result_to_json(schema_entry_point, output.as_deref().unwrap_or_default())
+                .map_err(|error| format!("invalid return value: {error:?}"))?,
+        ),
+        Err(_) => None,
+    };
+    let report = json!({
+        "returned": returned,
+        "error": result.err().map(|revert| format!("{revert:?}")),
+        "printed": env.printed()[printed_before..],
+        "storage": storage_diff(&storage_before, &env.storage()),
+    });
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    // The context keeps the address of the contract, so the next call finds it again.
+    env.save(state, format)?;
+    Ok(())
+}
+
+/// Makes the entry points of `C` callable at `address` in `env`, without running its installer
+/// there.
+fn reinstall<C: Contract>(env: &TestEnv, address: Address) -> Result<()> {
+    // Entry points are plain functions, so a copy installed on a scratch env provides them.
+    let scratch = TestEnv::new();
+    let installed = scratch
+        .deploy::<C>()
+        .map_err(|error| format!("failed to install contract: {error:?}"))?;
+    let entry_points: Vec<(&str, fn())> = C::schema()
+        .entry_points
+        .iter()
+        .filter_map(|entry_point| {
+            let export = scratch.entry_point(installed, entry_point.name)?;
+            Some((entry_point.name, export))
+        })
+        .collect();
+    env.run(|| host::register_contract(address, &entry_points));
+    Ok(())
+}
+
+/// Entries added, changed and removed between two snapshots of storage.
+fn storage_diff(before: &[StorageEntry], after: &[StorageEntry]) -> serde_json::Value {
+    let by_key = |entries: &[StorageEntry]| -> BTreeMap<_, _> {
+        entries
+            .iter()
+            .map(|entry| {
+                (
+                    (entry.contract, entry.key_space, entry.key.clone()),
+                    entry.clone(),
+                )
+            })
+            .collect()
+    };
+    let before = by_key(before);
+    let after = by_key(after);
+
+    let mut added = Vec::new();
+    let mut changed = Vec::new();
+    for (key, entry) in &after {
+        match before.get(key) {
+            None => added.push(entry),
+            Some(previous) if previous != entry => changed.push(entry),
+            Some(_) => {}
+        }
+    }
+    let removed: Vec<_> = before
+        .iter()
+        .filter(|(key, _)| !after.contains_key(*key))
+        .map(|(_, entry)| entry)
+        .collect();
+    json!({ "added": added, "changed": changed, "removed": removed })
+}
+
+/// Returns `wasm` with `schema` in its [`SCHEMA_SECTION`], replacing any schema already there.
+pub fn embed_schema(wasm: &[u8], schema: &[u8]) -> Result<Vec<u8>> {
+    let mut module = WASM_HEADER.to_vec();
+    for (id, payload) in sections(wasm)? {
+        if id == CUSTOM_SECTION_ID && custom_section(payload)?.0 == SCHEMA_SECTION.as_bytes() {
+            continue;
+        }
+        push_section(&mut module, id, payload);
+    }
+
+    let mut payload = Vec::new();
+    write_leb128(&mut payload, SCHEMA_SECTION.len());
+    payload.extend_from_slice(SCHEMA_SECTION.as_bytes());
+    payload.extend_from_slice(schema);
+    push_section(&mut module, CUSTOM_SECTION_ID, &payload);
+    Ok(module)
+}
+
+/// Contents of the [`SCHEMA_SECTION`] of `wasm`, if it has one.
+pub fn extract_schema(wasm: &[u8]) -> Result<Option<&[u8]>> {
+    for (id, payload) in sections(wasm)? {
+        if id == CUSTOM_SECTION_ID {
+            let (name, data) = custom_section(payload)?;
+            if name == SCHEMA_SECTION.as_bytes() {
+                return Ok(Some(data));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Splits a module into `(id, payload)` pairs, one per section.
+fn sections(wasm: &[u8]) -> Result<Vec<(u8, &[u8])>> {
+    let mut rest = wasm.strip_prefix(WASM_HEADER).ok_or("not a wasm module")?;
+    let mut sections = Vec::new();
+    while let Some((&id, tail)) = rest.split_first() {
+        let (size, tail) = read_leb128(tail)?;
+        if tail.len() < size {
+            return Err("truncated section".into());
+        }
+        let (payload, tail) = tail.split_at(size);
+        sections.push((id, payload));
+        rest = tail;
+    }
+    Ok(sections)
+}
+
+/// Splits the payload of a custom section into its name and data.
+fn custom_section(payload: &[u8]) -> Result<(&[u8], &[u8])> {
+    let (name_len, rest) = read_leb128(payload)?;
+    if rest.len() < name_len {
+        return Err("truncated custom section name".into());
+    }
+    Ok(rest.split_at(name_len))
+}
+
+fn push_section(module: &mut Vec<u8>, id: u8, payload: &[u8]) {
+    module.push(id);
+    write_leb128(module, payload.len());
+    module.extend_from_slice(payload);
+}
+
+fn read_leb128(bytes: &[u8]) -> Result<(usize, &[u8])> {
+    let mut value = 0;
+    for (index, byte) in bytes.iter().enumerate().take(5) {
+        value |= usize::from(byte & 0x7f) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Ok((value, &bytes[index + 1..]));
+        }
+    }
+    Err("malformed LEB128 integer".into())
+}
+
+fn write_leb128(bytes: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_section_round_trip() {
+        let wasm = wat::parse_str(r#"(module (func (export "call")))"#).unwrap();
+        assert_eq!(extract_schema(&wasm).unwrap(), None);
+
+        let schema = vec![b'x'; 200];
+        let embedded = embed_schema(&wasm, &schema).unwrap();
+        assert_eq!(extract_schema(&embedded).unwrap(), Some(&schema[..]));
+        // The module still loads, and embedding again replaces the schema.
+        wasmi::Module::new(&wasmi::Engine::default(), &embedded[..]).unwrap();
+        let replaced = embed_schema(&embedded, b"{}").unwrap();
+        assert_eq!(extract_schema(&replaced).unwrap(), Some(&b"{}"[..]));
+        assert_eq!(
+            replaced.len(),
+            wasm.len() + 2 + 1 + SCHEMA_SECTION.len() + 2
+        );
+
+        assert!(extract_schema(b"not wasm").is_err());
+    }
+
+    #[test]
+    fn diffs_storage() {
+        let entry = |key: &[u8], value: &[u8]| StorageEntry {
+            contract: None,
+            key_space: 0,
+            key: key.to_vec(),
+            tag: 0,
+            value: value.to_vec(),
+        };
+        let before = [entry(b"a", b"1"), entry(b"b", b"1"), entry(b"c", b"1")];
+        let after = [entry(b"a", b"1"), entry(b"b", b"2"), entry(b"d", b"1")];
+
+        let diff = storage_diff(&before, &after);
+        assert_eq!(diff["added"][0]["key"], hex::encode(b"d"));
+        assert_eq!(diff["changed"][0]["key"], hex::encode(b"b"));
+        assert_eq!(diff["changed"][0]["value"], hex::encode(b"2"));
+        assert_eq!(diff["removed"][0]["key"], hex::encode(b"c"));
+        assert_eq!(diff["added"].as_array().unwrap().len(), 1);
+    }
+}
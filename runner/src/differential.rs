@@ -0,0 +1,271 @@
+//! Runs the same scenario against the native host of `api` and a wasm module in the [`Runner`],
+//! and reports the first step where the two backends disagree.
+
+use std::fmt;
+
+use api::{
+    host::{self, Address, StorageEntry, TestEnv},
+    testing::{self, Revert},
+    RuntimeArgs,
+};
+
+use crate::{Error, Runner, Storage};
+
+enum Step {
+    Export {
+        name: String,
+        args: RuntimeArgs,
+        native: fn(),
+    },
+    Call {
+        address: Address,
+        entry_point: String,
+        args: RuntimeArgs,
+    },
+}
+
+/// Steps executed in order on a fresh chain.
+#[derive(Default)]
+pub struct Scenario {
+    steps: Vec<Step>,
+}
+
+impl Scenario {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs the export `name` of the module, and `native` in its place on the native host.
+    ///
+    /// `native` gets `args` through [`api::named_args`], like the export does.
+    pub fn export(mut self, name: &str, args: RuntimeArgs, native: fn()) -> Self {
+        self.steps.push(Step::Export {
+            name: name.to_string(),
+            args,
+            native,
+        });
+        self
+    }
+
+    /// Calls `entry_point` of a contract created by an earlier step.
+    pub fn call(mut self, address: Address, entry_point: &str, args: RuntimeArgs) -> Self {
+        self.steps.push(Step::Call {
+            address,
+            entry_point: entry_point.to_string(),
+            args,
+        });
+        self
+    }
+}
+
+/// How a step ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ending {
+    /// Returned normally, with the data passed to `ret`, if any.
+    Returned(Option<Vec<u8>>),
+    Reverted(u32),
+    /// Stopped in any other way, like a panic or a trap. The backends describe those
+    /// differently, so only the fact that it happened is compared.
+    Aborted,
+}
+
+/// What a step did, as compared between the backends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub ending: Ending,
+    /// Messages printed during the step.
+    pub printed: Vec<String>,
+    /// Every value in storage after the step.
+    pub storage: Vec<StorageEntry>,
+}
+
+/// First step whose outcome differs between the backends.
+#[derive(Debug)]
+pub struct Divergence {
+    /// Index of the step in the scenario.
+    pub step: usize,
+    pub native: Outcome,
+    pub wasm: Outcome,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "backends diverged at step {}", self.step)?;
+        writeln!(f, "native: {:#?}", self.native)?;
+        write!(f, "wasm: {:#?}", self.wasm)
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+/// Runs `scenario` natively and on `runner`, returning the outcomes of its steps if both
+/// backends agree on all of them.
+pub fn compare(mut runner: Runner, scenario: &Scenario) -> Result<Vec<Outcome>, Box<Divergence>> {
+    let mut env = TestEnv::new();
+    let mut outcomes = Vec::new();
+    for (step, action) in scenario.steps.iter().enumerate() {
+        let native = run_native(&mut env, action);
+        let wasm = run_wasm(&mut runner, action);
+        if native != wasm {
+            return Err(Box::new(Divergence { step, native, wasm }));
+        }
+        outcomes.push(native);
+    }
+    Ok(outcomes)
+}
+
+/// Runs `step` on a fork of `env`, which replaces `env` unless the step failed, so failed
+/// steps leave storage untouched as they do on the runner.
+fn run_native(env: &mut TestEnv, step: &Step) -> Outcome {
+    let attempt = env.fork();
+    let printed_before = attempt.printed().len();
+    let context = attempt.context();
+    let (entry_point, args) = match step {
+        Step::Export { native, args, .. } => (Some(*native), args),
+        Step::Call {
+            address,
+            entry_point,
+            args,
+        } => {
+            attempt.set_context(host::Context {
+                contract_address: Some(*address),
+                ..context.clone()
+            });
+            (attempt.entry_point(*address, entry_point), args)
+        }
+    };
+    attempt.set_args(args.clone());
+
+    let ending = match entry_point {
+        Some(entry_point) => attempt.run(|| match testing::call(entry_point) {
+            Ok(()) => Ending::Returned(host::take_return_data().map(|data| data.to_vec())),
+            Err(Revert::Code(code)) => Ending::Reverted(code),
+            Err(Revert::OutOfGas | Revert::Panic(_)) => Ending::Aborted,
+        }),
+        None => Ending::Aborted,
+    };
+    attempt.set_args(RuntimeArgs::new());
+    attempt.set_context(context);
+
+    let printed = attempt.printed().split_off(printed_before);
+    if let Ending::Returned(_) = ending {
+        *env = attempt;
+    }
+    Outcome {
+        ending,
+        printed,
+        storage: env.storage(),
+    }
+}
+
+fn run_wasm(runner: &mut Runner, step: &Step) -> Outcome {
+    let printed_before = runner.printed().len();
+    let result = match step {
+        Step::Export { name, args, .. } => runner.call(name, &encode(args)),
+        Step::Call {
+            address,
+            entry_point,
+            args,
+        } => runner.call_contract(*address, entry_point, &encode(args)),
+    };
+    let ending = match result {
+        Ok(data) => Ending::Returned(data),
+        Err(Error::Revert(code)) => Ending::Reverted(code),
+        Err(_) => Ending::Aborted,
+    };
+    Outcome {
+        ending,
+        printed: runner.printed()[printed_before..].to_vec(),
+        storage: storage_entries(runner.storage()),
+    }
+}
+
+fn encode(args: &RuntimeArgs) -> Vec<u8> {
+    borsh::to_vec(args).expect("args should encode")
+}
+
+fn storage_entries(storage: &Storage) -> Vec<StorageEntry> {
+    storage
+        .iter()
+        .map(|((contract, key_space, key), (tag, value))| StorageEntry {
+            contract: *contract,
+            key_space: *key_space,
+            key: key.clone(),
+            tag: *tag,
+            value: value.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `stored` under `key` and prints it, then reverts with code 5 on `fail`.
+    const MODULE: &str = r#"
+        (module
+            (import "env" "memory" (memory 1))
+            (import "env" "casper_write"
+                (func $write (param i64 i32 i32 i64 i32 i32) (result i32)))
+            (import "env" "casper_print" (func $print (param i32 i32) (result i32)))
+            (import "env" "casper_revert" (func $revert (param i32)))
+            (data (i32.const 0) "key")
+            (data (i32.const 8) "stored")
+            (func (export "store")
+                (drop (call $write
+                    (i64.const 0) (i32.const 0) (i32.const 3)
+                    (i64.const 1) (i32.const 8) (i32.const 6)))
+                (drop (call $print (i32.const 8) (i32.const 6))))
+            (func (export "fail")
+                (drop (call $write
+                    (i64.const 0) (i32.const 0) (i32.const 3)
+                    (i64.const 1) (i32.const 0) (i32.const 3)))
+                (call $revert (i32.const 5))))
+    "#;
+
+    fn runner() -> Runner {
+        Runner::new(&wat::parse_str(MODULE).unwrap()).unwrap()
+    }
+
+    fn store() {
+        host::write(0, b"key", 1, b"stored").unwrap();
+        host::print("stored");
+    }
+
+    fn fail() {
+        host::write(0, b"key", 1, b"key").unwrap();
+        host::revert(5);
+    }
+
+    #[test]
+    fn agreeing_backends() {
+        let scenario = Scenario::new()
+            .export("store", RuntimeArgs::new(), store)
+            .export("fail", RuntimeArgs::new(), fail);
+        let outcomes = compare(runner(), &scenario).unwrap();
+
+        assert_eq!(outcomes[0].ending, Ending::Returned(None));
+        assert_eq!(outcomes[0].printed, ["stored"]);
+        assert_eq!(outcomes[1].ending, Ending::Reverted(5));
+        assert_eq!(outcomes[1].storage, outcomes[0].storage);
+        assert_eq!(outcomes[1].storage[0].value, b"stored");
+    }
+
+    #[test]
+    fn reports_first_divergence() {
+        fn store_elsewhere() {
+            host::write(0, b"other key", 1, b"stored").unwrap();
+            host::print("stored");
+        }
+
+        let scenario = Scenario::new()
+            .export("store", RuntimeArgs::new(), store)
+            .export("store", RuntimeArgs::new(), store_elsewhere)
+            .export("fail", RuntimeArgs::new(), store);
+        let divergence = compare(runner(), &scenario).unwrap_err();
+
+        assert_eq!(divergence.step, 1);
+        assert_eq!(divergence.native.storage.len(), 2);
+        assert_eq!(divergence.wasm.storage.len(), 1);
+    }
+}
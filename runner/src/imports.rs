@@ -0,0 +1,380 @@
+//! The `casper_*` host functions provided to modules.
+
+use std::{collections::BTreeMap, mem};
+
+use api::host::{Address, Context, Entity, EntityInfo, Error as HostError, MAX_VALUE_SIZE};
+use wasmi::{
+    AsContext, AsContextMut, Caller, Error, Extern, ExternType, Func, Instance, Linker, Module,
+    Store, Val,
+};
+
+use super::{HostState, Revert};
+
+/// Size of `api::host::EntryPoint` on wasm32: five 32-bit fields.
+const ENTRY_POINT_SIZE: usize = 20;
+
+/// Defines the host functions on `linker`, and makes every other function imported by `module`
+/// trap when called, so modules using host functions the runner lacks still instantiate.
+pub(super) fn define(
+    linker: &mut Linker<HostState>,
+    store: &mut Store<HostState>,
+    module: &Module,
+) -> Result<(), Error> {
+    linker.func_wrap("env", "casper_read", casper_read)?;
+    linker.func_wrap("env", "casper_write", casper_write)?;
+    linker.func_wrap("env", "casper_print", casper_print)?;
+    linker.func_wrap("env", "casper_revert", casper_revert)?;
+    linker.func_wrap("env", "casper_return", casper_return)?;
+    linker.func_wrap("env", "casper_copy_input", casper_copy_input)?;
+    linker.func_wrap("env", "casper_env_caller", casper_env_caller)?;
+    linker.func_wrap("env", "casper_env_initiator", casper_env_initiator)?;
+    linker.func_wrap(
+        "env",
+        "casper_env_contract_address",
+        casper_env_contract_address,
+    )?;
+    linker.func_wrap(
+        "env",
+        "casper_env_block_time",
+        |caller: Caller<'_, HostState>| caller.data().context.block_time as i64,
+    )?;
+    linker.func_wrap(
+        "env",
+        "casper_env_block_height",
+        |caller: Caller<'_, HostState>| caller.data().context.block_height as i64,
+    )?;
+    linker.func_wrap(
+        "env",
+        "casper_env_attached_value",
+        |caller: Caller<'_, HostState>| caller.data().context.attached_value as i64,
+    )?;
+    linker.func_wrap("env", "casper_create_contract", casper_create_contract)?;
+    linker.func_wrap("env", "casper_call", casper_call)?;
+
+    for import in module.imports() {
+        if let ExternType::Func(ty) = import.ty() {
+            let name = import.name().to_string();
+            let unsupported = Func::new(&mut *store, ty.clone(), move |_, _, _| {
+                Err(Error::new(format!(
+                    "host function `{name}` is not supported"
+                )))
+            });
+            // Fails for the functions defined above, which is fine.
+            let _ = linker.define(import.module(), import.name(), unsupported);
+        }
+    }
+    Ok(())
+}
+
+fn memory(caller: &Caller<'_, HostState>) -> wasmi::Memory {
+    caller.data().memory.expect("module should import memory")
+}
+
+/// Copies `size` bytes at `ptr` out of guest memory, trapping if they are not all inside it.
+fn read_bytes(caller: &Caller<'_, HostState>, ptr: i32, size: i32) -> Result<Vec<u8>, Error> {
+    let (ptr, size) = (ptr as u32 as usize, size as u32 as usize);
+    // Checked before allocating anything, since the guest picks `size`.
+    ptr.checked_add(size)
+        .and_then(|end| memory(caller).data(caller).get(ptr..end))
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| Error::new(format!("{size} bytes at {ptr} are out of bounds")))
+}
+
+fn write_bytes(caller: &mut Caller<'_, HostState>, ptr: i32, bytes: &[u8]) -> Result<(), Error> {
+    memory(caller).write(caller, ptr as u32 as usize, bytes)?;
+    Ok(())
+}
+
+fn read_address(caller: &Caller<'_, HostState>, ptr: i32, size: i32) -> Result<Address, Error> {
+    read_bytes(caller, ptr, size)?
+        .try_into()
+        .map_err(|_| Error::new("address should be 32 bytes long"))
+}
+
+/// Name under which `--export-table` exports the function table.
+const TABLE_EXPORT: &str = "__indirect_function_table";
+
+/// Looks up function `index` of the table exported by `instance`.
+///
+/// Function pointers of the guest, like entry points and `alloc` callbacks, are indices into
+/// this table.
+pub(super) fn table_func(
+    ctx: impl AsContext,
+    instance: Instance,
+    index: u32,
+) -> Result<Func, Error> {
+    let table = instance
+        .get_table(&ctx, TABLE_EXPORT)
+        .ok_or_else(|| Error::new("module should export its function table"))?;
+    match table.get(&ctx, index) {
+        Some(Val::FuncRef(func)) => func.func().copied(),
+        _ => None,
+    }
+    .ok_or_else(|| Error::new(format!("table has no function at {index}")))
+}
+
+fn caller_table_func(caller: &Caller<'_, HostState>, index: u32) -> Result<Func, Error> {
+    let table = caller
+        .get_export(TABLE_EXPORT)
+        .and_then(Extern::into_table)
+        .ok_or_else(|| Error::new("module should export its function table"))?;
+    match table.get(caller, index) {
+        Some(Val::FuncRef(func)) => func.func().copied(),
+        _ => None,
+    }
+    .ok_or_else(|| Error::new(format!("table has no function at {index}")))
+}
+
+/// Asks the guest for a buffer through its `alloc` callback and copies `data` into it.
+fn copy_through_alloc(
+    caller: &mut Caller<'_, HostState>,
+    alloc: i32,
+    alloc_ctx: i32,
+    data: &[u8],
+) -> Result<i32, Error> {
+    let alloc = caller_table_func(caller, alloc as u32)?;
+    let ptr = alloc
+        .typed::<(i32, i32), i32>(&*caller)?
+        .call(caller.as_context_mut(), (data.len() as i32, alloc_ctx))?;
+    write_bytes(caller, ptr, data)?;
+    Ok(ptr)
+}
+
+fn casper_read(
+    mut caller: Caller<'_, HostState>,
+    key_space: i64,
+    key_ptr: i32,
+    key_size: i32,
+    info_ptr: i32,
+    alloc: i32,
+    alloc_ctx: i32,
+) -> Result<i32, Error> {
+    let key = read_bytes(&caller, key_ptr, key_size)?;
+    let state = caller.data();
+    let stored = state
+        .storage
+        .get(&(state.context.contract_address, key_space as u64, key))
+        .cloned();
+    let Some((tag, value)) = stored else {
+        return Ok(HostError::NotFound.code());
+    };
+    let data = copy_through_alloc(&mut caller, alloc, alloc_ctx, &value)?;
+    // `ReadInfo { data: *const u8, size: usize, tag: u64 }` as laid out on wasm32.
+    let mut info = Vec::new();
+    info.extend_from_slice(&data.to_le_bytes());
+    info.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    info.extend_from_slice(&tag.to_le_bytes());
+    write_bytes(&mut caller, info_ptr, &info)?;
+    Ok(0)
+}
+
+fn casper_write(
+    mut caller: Caller<'_, HostState>,
+    key_space: i64,
+    key_ptr: i32,
+    key_size: i32,
+    value_tag: i64,
+    value_ptr: i32,
+    value_size: i32,
+) -> Result<i32, Error> {
+    if value_size as u32 as usize > MAX_VALUE_SIZE {
+        return Ok(HostError::ValueTooLarge.code());
+    }
+    let key = read_bytes(&caller, key_ptr, key_size)?;
+    let value = read_bytes(&caller, value_ptr, value_size)?;
+    let state = caller.data_mut();
+    let contract = state.context.contract_address;
+    state
+        .storage
+        .insert((contract, key_space as u64, key), (value_tag as u64, value));
+    Ok(0)
+}
+
+fn casper_print(
+    mut caller: Caller<'_, HostState>,
+    msg_ptr: i32,
+    msg_size: i32,
+) -> Result<i32, Error> {
+    let msg = read_bytes(&caller, msg_ptr, msg_size)?;
+    let msg = String::from_utf8_lossy(&msg).into_owned();
+    eprintln!("🕸️ {msg}");
+    caller.data_mut().printed.push(msg);
+    Ok(0)
+}
+
+fn casper_revert(_caller: Caller<'_, HostState>, code: i32) -> Result<(), Error> {
+    Err(Error::host(Revert(code as u32)))
+}
+
+fn casper_return(
+    mut caller: Caller<'_, HostState>,
+    data_ptr: i32,
+    data_size: i32,
+) -> Result<(), Error> {
+    let data = read_bytes(&caller, data_ptr, data_size)?;
+    caller.data_mut().return_data = Some(data);
+    Ok(())
+}
+
+fn casper_copy_input(
+    mut caller: Caller<'_, HostState>,
+    alloc: i32,
+    alloc_ctx: i32,
+) -> Result<i32, Error> {
+    let input = caller.data().input.clone();
+    copy_through_alloc(&mut caller, alloc, alloc_ctx, &input)?;
+    Ok(0)
+}
+
+fn casper_env_caller(mut caller: Caller<'_, HostState>, info_ptr: i32) -> Result<i32, Error> {
+    let info = EntityInfo::from_entity(caller.data().context.caller);
+    let mut bytes = info.kind.to_le_bytes().to_vec();
+    bytes.extend_from_slice(&info.address);
+    write_bytes(&mut caller, info_ptr, &bytes)?;
+    Ok(0)
+}
+
+fn casper_env_initiator(mut caller: Caller<'_, HostState>, address_ptr: i32) -> Result<i32, Error> {
+    let initiator = caller.data().context.initiator;
+    write_bytes(&mut caller, address_ptr, &initiator)?;
+    Ok(0)
+}
+
+fn casper_env_contract_address(
+    mut caller: Caller<'_, HostState>,
+    address_ptr: i32,
+) -> Result<i32, Error> {
+    match caller.data().context.contract_address {
+        Some(address) => {
+            write_bytes(&mut caller, address_ptr, &address)?;
+            Ok(0)
+        }
+        None => Ok(HostError::NotFound.code()),
+    }
+}
+
+fn casper_create_contract(
+    mut caller: Caller<'_, HostState>,
+    entry_points_ptr: i32,
+    entry_points_size: i32,
+    address_ptr: i32,
+) -> Result<i32, Error> {
+    let descriptors_size = u32::try_from(entry_points_size)
+        .ok()
+        .and_then(|count| count.checked_mul(ENTRY_POINT_SIZE as u32))
+        .ok_or_else(|| Error::new(format!("{entry_points_size} entry points are too many")))?;
+    let descriptors = read_bytes(&caller, entry_points_ptr, descriptors_size as i32)?;
+    let mut entry_points = BTreeMap::new();
+    for descriptor in descriptors.chunks(ENTRY_POINT_SIZE) {
+        let field = |index: usize| {
+            let bytes = &descriptor[index * 4..index * 4 + 4];
+            i32::from_le_bytes(bytes.try_into().unwrap())
+        };
+        let name = read_bytes(&caller, field(0), field(1))?;
+        let Ok(name) = String::from_utf8(name) else {
+            return Ok(HostError::Serialization.code());
+        };
+        // Function pointers are indices into the function table.
+        entry_points.insert(name, field(4) as u32);
+    }
+
+    let state = caller.data_mut();
+    state.created_contracts += 1;
+    let mut address = [0; 32];
+    address[..8].copy_from_slice(&state.created_contracts.to_be_bytes());
+    state.contracts.insert(address, entry_points);
+    write_bytes(&mut caller, address_ptr, &address)?;
+    Ok(0)
+}
+
+/// Moves `amount` from `source` to `target`, changing neither balance if that fails.
+fn move_balance(
+    balances: &mut BTreeMap<Entity, u64>,
+    source: Entity,
+    target: Entity,
+    amount: u64,
+) -> Result<(), HostError> {
+    let balance = |entity| balances.get(&entity).copied().unwrap_or_default();
+    let source_balance = balance(source)
+        .checked_sub(amount)
+        .ok_or(HostError::InsufficientFunds)?;
+    if source == target {
+        return Ok(());
+    }
+    let target_balance = balance(target)
+        .checked_add(amount)
+        .ok_or(HostError::BalanceOverflow)?;
+    balances.insert(source, source_balance);
+    balances.insert(target, target_balance);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn casper_call(
+    mut caller: Caller<'_, HostState>,
+    address_ptr: i32,
+    address_size: i32,
+    entry_point_ptr: i32,
+    entry_point_size: i32,
+    input_ptr: i32,
+    input_size: i32,
+    value: i64,
+    alloc: i32,
+    alloc_ctx: i32,
+) -> Result<i32, Error> {
+    let address = read_address(&caller, address_ptr, address_size)?;
+    let entry_point = read_bytes(&caller, entry_point_ptr, entry_point_size)?;
+    let input = read_bytes(&caller, input_ptr, input_size)?;
+    let index = String::from_utf8(entry_point).ok().and_then(|entry_point| {
+        caller
+            .data()
+            .contracts
+            .get(&address)?
+            .get(&entry_point)
+            .copied()
+    });
+    let Some(index) = index else {
+        return Ok(HostError::NotFound.code());
+    };
+    let export = caller_table_func(&caller, index)?;
+
+    let state = caller.data_mut();
+    let current = match state.context.contract_address {
+        Some(contract_address) => Entity::Contract(contract_address),
+        None => state.context.caller,
+    };
+    let snapshot = (state.storage.clone(), state.balances.clone());
+    if let Err(error) = move_balance(
+        &mut state.balances,
+        current,
+        Entity::Contract(address),
+        value as u64,
+    ) {
+        return Ok(error.code());
+    }
+    let callee_context = Context {
+        caller: current,
+        contract_address: Some(address),
+        attached_value: value as u64,
+        ..state.context.clone()
+    };
+    let caller_context = mem::replace(&mut state.context, callee_context);
+    let caller_input = mem::replace(&mut state.input, input);
+    let caller_return_data = state.return_data.take();
+
+    let result = export.call(&mut caller, &[], &mut []);
+
+    let state = caller.data_mut();
+    state.context = caller_context;
+    state.input = caller_input;
+    let return_data = mem::replace(&mut state.return_data, caller_return_data);
+    if result.is_err() {
+        // Discards the writes of the callee along with the attached tokens.
+        (state.storage, state.balances) = snapshot;
+        return Ok(HostError::CalleeReverted.code());
+    }
+    if let Some(return_data) = return_data {
+        copy_through_alloc(&mut caller, alloc, alloc_ctx, &return_data)?;
+    }
+    Ok(0)
+}
@@ -0,0 +1,422 @@
+//! Executes contracts compiled to wasm in a pure-Rust interpreter, against an in-memory store.
+//!
+//! Modules are expected to be built the way `test-contract` is, with `--import-memory` and
+//! `--export-table`: the runner supplies the memory, and calls back into the guest through the
+//! exported function table to allocate buffers and run entry points.
+
+pub mod cli;
+pub mod differential;
+mod imports;
+
+use std::{collections::BTreeMap, fmt};
+
+use api::host::{Address, Context, Entity};
+use wasmi::{
+    core::HostError, Engine, ExternType, Func, Instance, Linker, Memory, Module, Store, Val,
+};
+
+/// Stored values by contract, key space and key, as `(tag, value)` pairs. Code executed
+/// outside of a contract uses the `None` contract.
+pub type Storage = BTreeMap<(Option<Address>, u64, Vec<u8>), (u64, Vec<u8>)>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Module could not be compiled or instantiated.
+    Instantiate(wasmi::Error),
+    /// Module has no exported function with this name.
+    MissingExport(String),
+    /// Contract reverted with this code.
+    Revert(u32),
+    /// Execution trapped, for example on `unreachable` or a host function the runner lacks.
+    Trap(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Instantiate(error) => write!(f, "failed to instantiate module: {error}"),
+            Error::MissingExport(name) => write!(f, "module does not export `{name}`"),
+            Error::Revert(code) => write!(f, "reverted with code {code}"),
+            Error::Trap(message) => write!(f, "trapped: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<wasmi::Error> for Error {
+    fn from(error: wasmi::Error) -> Self {
+        match error.downcast_ref::<Revert>() {
+            Some(Revert(code)) => Error::Revert(*code),
+            None => Error::Trap(error.to_string()),
+        }
+    }
+}
+
+/// Raised by `casper_revert` to unwind out of the guest.
+#[derive(Debug, Clone, Copy)]
+struct Revert(u32);
+
+impl fmt::Display for Revert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "revert with code {}", self.0)
+    }
+}
+
+impl HostError for Revert {}
+
+/// State the host functions operate on.
+#[derive(Default)]
+struct HostState {
+    /// Memory imported by the module; set right after the store is created.
+    memory: Option<Memory>,
+    storage: Storage,
+    balances: BTreeMap<Entity, u64>,
+    printed: Vec<String>,
+    /// Borsh-encoded named arguments of the current call.
+    input: Vec<u8>,
+    return_data: Option<Vec<u8>>,
+    context: Context,
+    /// Entry points of created contracts, as indices into the exported function table.
+    contracts: BTreeMap<Address, BTreeMap<String, u32>>,
+    created_contracts: u64,
+}
+
+/// An instantiated module together with the chain state it executes against.
+pub struct Runner {
+    store: Store<HostState>,
+    instance: Instance,
+}
+
+impl Runner {
+    pub fn new(wasm: &[u8]) -> Result<Self, Error> {
+        let engine = Engine::default();
+        let module = Module::new(&engine, wasm).map_err(Error::Instantiate)?;
+        let mut store = Store::new(&engine, HostState::default());
+        let mut linker = Linker::new(&engine);
+        imports::define(&mut linker, &mut store, &module).map_err(Error::Instantiate)?;
+        for import in module.imports() {
+            if let ExternType::Memory(ty) = import.ty() {
+                let memory = Memory::new(&mut store, *ty).map_err(wasmi::Error::from)?;
+                store.data_mut().memory = Some(memory);
+                linker
+                    .define(import.module(), import.name(), memory)
+                    .map_err(wasmi::Error::from)?;
+            }
+        }
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(Error::Instantiate)?;
+        Ok(Self { store, instance })
+    }
+
+    /// Sets the execution context reported to the module.
+    pub fn set_context(&mut self, context: Context) {
+        self.store.data_mut().context = context;
+    }
+
+    /// Runs `export` with the Borsh-encoded named arguments `input`, returning the data it passed
+    /// to `casper_return`.
+    ///
+    /// Storage and balance changes are discarded if the export reverts or traps.
+    pub fn call(&mut self, export: &str, input: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let func = self.export(export)?;
+        self.invoke(func, input.to_vec(), &[])
+    }
+
+    /// Runs `export`, passing each of `args` as a pointer to a `Slice` in guest memory.
+    ///
+    /// Buffers are allocated through the `alloc` export of the module.
+    pub fn call_with_slices(
+        &mut self,
+        export: &str,
+        args: &[&[u8]],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let func = self.export(export)?;
+        let alloc = self.export("alloc")?;
+        let mut params = Vec::new();
+        for arg in args {
+            let data = self.copy_to_guest(alloc, arg)?;
+            let mut slice = Vec::new();
+            slice.extend_from_slice(&data.to_le_bytes());
+            slice.extend_from_slice(&(arg.len() as u32).to_le_bytes());
+            params.push(Val::I32(self.copy_to_guest(alloc, &slice)? as i32));
+        }
+        self.invoke(func, Vec::new(), &params)
+    }
+
+    /// Calls `entry_point` of a contract the module created with `casper_create_contract`.
+    pub fn call_contract(
+        &mut self,
+        address: Address,
+        entry_point: &str,
+        input: &[u8],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let state = self.store.data();
+        let index = state
+            .contracts
+            .get(&address)
+            .and_then(|entry_points| entry_points.get(entry_point))
+            .copied()
+            .ok_or_else(|| Error::MissingExport(entry_point.to_string()))?;
+        let func = imports::table_func(&self.store, self.instance, index)?;
+        let caller = self.store.data().context.clone();
+        self.store.data_mut().context = Context {
+            contract_address: Some(address),
+            ..caller.clone()
+        };
+        let result = self.invoke(func, input.to_vec(), &[]);
+        self.store.data_mut().context = caller;
+        result
+    }
+
+    fn export(&self, name: &str) -> Result<Func, Error> {
+        self.instance
+            .get_func(&self.store, name)
+            .ok_or_else(|| Error::MissingExport(name.to_string()))
+    }
+
+    /// Allocates a guest buffer with `alloc` and copies `data` into it, returning its address.
+    fn copy_to_guest(&mut self, alloc: Func, data: &[u8]) -> Result<u32, Error> {
+        let ptr = alloc
+            .typed::<i32, i32>(&self.store)?
+            .call(&mut self.store, data.len() as i32)? as u32;
+        let memory = self
+            .store
+            .data()
+            .memory
+            .expect("module should import memory");
+        memory
+            .write(&mut self.store, ptr as usize, data)
+            .map_err(wasmi::Error::from)?;
+        Ok(ptr)
+    }
+
+    fn invoke(
+        &mut self,
+        func: Func,
+        input: Vec<u8>,
+        params: &[Val],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let state = self.store.data_mut();
+        state.input = input;
+        state.return_data = None;
+        let snapshot = (state.storage.clone(), state.balances.clone());
+        let result = func.call(&mut self.store, params, &mut []);
+        let state = self.store.data_mut();
+        state.input.clear();
+        if let Err(error) = result {
+            (state.storage, state.balances) = snapshot;
+            return Err(error.into());
+        }
+        Ok(state.return_data.take())
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.store.data().storage
+    }
+
+    pub fn balance(&self, entity: Entity) -> u64 {
+        self.store
+            .data()
+            .balances
+            .get(&entity)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn set_balance(&mut self, entity: Entity, amount: u64) {
+        self.store.data_mut().balances.insert(entity, amount);
+    }
+
+    /// Messages passed to `casper_print`, oldest first.
+    pub fn printed(&self) -> &[String] {
+        &self.store.data().printed
+    }
+
+    /// Addresses of the contracts created by the module.
+    pub fn contracts(&self) -> Vec<Address> {
+        self.store.data().contracts.keys().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use api::host::Error as HostError;
+
+    use super::*;
+
+    /// Stores and prints values through the host, laid out like code built from `api` would.
+    const MODULE: &str = r#"
+        (module
+            (import "env" "memory" (memory 1))
+            (import "env" "casper_write"
+                (func $write (param i64 i32 i32 i64 i32 i32) (result i32)))
+            (import "env" "casper_read"
+                (func $read (param i64 i32 i32 i32 i32 i32) (result i32)))
+            (import "env" "casper_print" (func $print (param i32 i32) (result i32)))
+            (import "env" "casper_revert" (func $revert (param i32)))
+            (import "env" "casper_emit" (func $emit (param i32 i32) (result i32)))
+            (table (export "__indirect_function_table") 2 funcref)
+            (elem (i32.const 1) $alloc_cb)
+            (global $heap (mut i32) (i32.const 1024))
+            (func $alloc (export "alloc") (param $len i32) (result i32)
+                (global.get $heap)
+                (global.set $heap (i32.add (global.get $heap) (local.get $len))))
+            (func $alloc_cb (param $len i32) (param $ctx i32) (result i32)
+                (call $alloc (local.get $len)))
+            (func $store (export "store") (param $key i32) (param $value i32)
+                (drop (call $write
+                    (i64.const 0)
+                    (i32.load (local.get $key)) (i32.load offset=4 (local.get $key))
+                    (i64.const 7)
+                    (i32.load (local.get $value)) (i32.load offset=4 (local.get $value)))))
+            (func (export "print") (param $key i32)
+                (if (i32.eqz (call $read
+                        (i64.const 0)
+                        (i32.load (local.get $key)) (i32.load offset=4 (local.get $key))
+                        (i32.const 16) (i32.const 1) (i32.const 0)))
+                    (then (drop (call $print
+                        (i32.load (i32.const 16)) (i32.load (i32.const 20)))))))
+            (func (export "store_and_revert") (param $key i32) (param $value i32)
+                (call $store (local.get $key) (local.get $value))
+                (call $revert (i32.const 5)))
+            (func (export "emit")
+                (drop (call $emit (i32.const 0) (i32.const 0))))
+            (func (export "print_past_memory")
+                (drop (call $print (i32.const 16) (i32.const -1)))))
+    "#;
+
+    fn runner() -> Runner {
+        Runner::new(&wat::parse_str(MODULE).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn reads_back_written_values() {
+        let mut runner = runner();
+        runner
+            .call_with_slices("store", &[b"greeting", b"hello"])
+            .unwrap();
+        runner.call_with_slices("print", &[b"greeting"]).unwrap();
+        runner.call_with_slices("print", &[b"missing"]).unwrap();
+
+        assert_eq!(
+            runner.storage().get(&(None, 0, b"greeting".to_vec())),
+            Some(&(7, b"hello".to_vec()))
+        );
+        assert_eq!(runner.printed(), ["hello"]);
+    }
+
+    #[test]
+    fn reverts_discard_storage_changes() {
+        let mut runner = runner();
+        let result = runner.call_with_slices("store_and_revert", &[b"key", b"value"]);
+
+        assert!(matches!(result, Err(Error::Revert(5))));
+        assert!(runner.storage().is_empty());
+    }
+
+    /// Creates a contract with a `deposit` entry point, and pays into it through `casper_call`,
+    /// returning the result code.
+    const PAYING_MODULE: &str = r#"
+        (module
+            (import "env" "memory" (memory 1))
+            (import "env" "casper_create_contract"
+                (func $create (param i32 i32 i32) (result i32)))
+            (import "env" "casper_call"
+                (func $call (param i32 i32 i32 i32 i32 i32 i64 i32 i32) (result i32)))
+            (import "env" "casper_return" (func $return (param i32 i32)))
+            (import "env" "casper_revert" (func $revert (param i32)))
+            (table (export "__indirect_function_table") 3 funcref)
+            (elem (i32.const 1) $deposit $deposit_and_revert)
+            (data (i32.const 0) "deposit")
+            (data (i32.const 8) "deposit_and_revert")
+            ;; Entry point descriptors: name, name length, params, params length, function.
+            (data (i32.const 32) "\00\00\00\00\07\00\00\00\00\00\00\00\00\00\00\00\01\00\00\00")
+            (data (i32.const 52) "\08\00\00\00\12\00\00\00\00\00\00\00\00\00\00\00\02\00\00\00")
+            (func $deposit)
+            (func $deposit_and_revert (call $revert (i32.const 1)))
+            (func (export "install")
+                (drop (call $create (i32.const 32) (i32.const 2) (i32.const 128))))
+            (func $pay (param $name i32) (param $name_len i32)
+                (i32.store (i32.const 256) (call $call
+                    (i32.const 128) (i32.const 32)
+                    (local.get $name) (local.get $name_len)
+                    (i32.const 0) (i32.const 0)
+                    (i64.const 30) (i32.const 0) (i32.const 0)))
+                (call $return (i32.const 256) (i32.const 4)))
+            (func (export "pay") (call $pay (i32.const 0) (i32.const 7)))
+            (func (export "pay_and_revert") (call $pay (i32.const 8) (i32.const 18)))
+            (func $install_many (param $count i32)
+                (drop (call $create (i32.const 32) (local.get $count) (i32.const 128))))
+            (func (export "install_past_memory") (call $install_many (i32.const 4096)))
+            (func (export "install_too_many") (call $install_many (i32.const 0x7fffffff)))
+            (func (export "install_negative") (call $install_many (i32.const -1))))
+    "#;
+
+    #[test]
+    fn calls_move_attached_value() {
+        let mut runner = Runner::new(&wat::parse_str(PAYING_MODULE).unwrap()).unwrap();
+        runner.call("install", &[]).unwrap();
+        let contract = Entity::Contract(runner.contracts()[0]);
+        let account = Entity::Account([0; 32]);
+        let code = |result: Result<Option<Vec<u8>>, Error>| {
+            i32::from_le_bytes(result.unwrap().unwrap().try_into().unwrap())
+        };
+
+        assert_eq!(
+            code(runner.call("pay", &[])),
+            HostError::InsufficientFunds.code()
+        );
+        runner.set_balance(account, 100);
+        assert_eq!(code(runner.call("pay", &[])), 0);
+        assert_eq!(runner.balance(account), 70);
+        assert_eq!(runner.balance(contract), 30);
+
+        // Reverted callees give the tokens back.
+        assert_eq!(
+            code(runner.call("pay_and_revert", &[])),
+            HostError::CalleeReverted.code()
+        );
+        assert_eq!(runner.balance(account), 70);
+        assert_eq!(runner.balance(contract), 30);
+
+        runner.set_balance(contract, u64::MAX);
+        assert_eq!(
+            code(runner.call("pay", &[])),
+            HostError::BalanceOverflow.code()
+        );
+        assert_eq!(runner.balance(account), 70);
+    }
+
+    #[test]
+    fn out_of_bounds_guest_buffers_trap() {
+        let mut runner = runner();
+        assert!(matches!(
+            runner.call("print_past_memory", &[]),
+            Err(Error::Trap(_))
+        ));
+
+        let mut runner = Runner::new(&wat::parse_str(PAYING_MODULE).unwrap()).unwrap();
+        for export in [
+            "install_past_memory",
+            "install_too_many",
+            "install_negative",
+        ] {
+            assert!(matches!(runner.call(export, &[]), Err(Error::Trap(_))));
+        }
+        assert!(runner.contracts().is_empty());
+    }
+
+    #[test]
+    fn unsupported_imports_trap_when_called() {
+        let mut runner = runner();
+
+        assert!(matches!(runner.call("emit", &[]), Err(Error::Trap(_))));
+        assert!(matches!(
+            runner.call("absent", &[]),
+            Err(Error::MissingExport(_))
+        ));
+    }
+}
@@ -3,8 +3,6 @@
 
 #[macro_use]
 extern crate alloc;
-use core::ptr::NonNull;
-
 use alloc::vec::Vec;
 use alloc::string::String;
 use api::Value;
@@ -20,6 +18,7 @@ struct Flipper {
 #[casper(entry_points)]
 impl Flipper {
     pub fn flip(&mut self, argument1: bool, argument2: String) {
+        api::host::print(&format!("flip argument1={argument1} argument2={argument2}"));
         let mut value = self.flag.get().unwrap().unwrap_or_default();
         value = !value;
         self.flag.set(value).unwrap();
@@ -28,27 +27,36 @@ impl Flipper {
     pub fn flag_value(&self) -> bool {
         self.flag.get().unwrap().unwrap_or_default()
     }
+
+    /// Reverts with code 1 unless the flag is set.
+    pub fn require_flag(&self) {
+        if !self.flag_value() {
+            api::host::revert(1);
+        }
+    }
 }
 
 // extern "C" fn flip(arg1: *const Slice, arg2: *const Slice);
 
+// Natively, the exports are only called by tests.
+#[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
 mod exports {
 
     use alloc::string::String;
     use alloc::vec::Vec;
-    use api::{
-        host::{self, EntryPoint, Param, Slice},
-        reserve_vec_space,
-    };
+    use api::{host, reserve_vec_space, Contract};
     use macros::casper;
 
+    use super::Flipper;
+
     // use crate::reserve_vec_space;
 
     const KEY_SPACE_DEFAULT: u64 = 0;
     const TAG_BYTES: u64 = 0;
 
-    fn mangled_entry_point(param_1: *const Slice, param_2: *const Slice) {
-        host::revert(123);
+    #[casper(export)]
+    pub fn echo(message: &str, #[casper(default = 1)] times: u32) {
+        host::print(&message.repeat(times as usize));
     }
 
     #[casper(export)]
@@ -84,50 +92,22 @@ mod exports {
 
         host::write(KEY_SPACE_DEFAULT, b"read back", TAG_BYTES, msg.as_bytes()).unwrap();
 
-        const NAME: &str = "test_fptr";
-
-        const PARAM_1: &str = "param_1";
-        const PARAM_2: &str = "param_2";
-
-        let params = [
-            Param {
-                name_ptr: PARAM_1.as_ptr(),
-                name_len: PARAM_1.len(),
-                ty: 0,
-            },
-            Param {
-                name_ptr: PARAM_2.as_ptr(),
-                name_len: PARAM_2.len(),
-                ty: 0,
-            },
-        ];
-
-        extern "C" fn mangled_entry_point_wrapper(param_1: *const Slice, param_2: *const Slice) {
-            mangled_entry_point(param_1, param_2)
-        }
-
-        let fptr_void: *const core::ffi::c_void = mangled_entry_point_wrapper as _;
-
-        let entry_point = EntryPoint {
-            name_ptr: NAME.as_ptr(),
-            name_len: NAME.len(),
-            params_ptr: params.as_ptr(),
-            params_size: params.len(),
-            fptr: fptr_void,
-        };
-        host::print(&format!("{entry_point:?}"));
+        let address = Flipper::install().expect("should install contract");
+        host::print(&format!("installed Flipper at {address:?}"));
         // host::revert(123);
     }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-fn main() {
-    todo!()
+fn main() -> std::process::ExitCode {
+    runner::cli::main::<Flipper>(runner::cli::Package {
+        manifest_dir: env!("CARGO_MANIFEST_DIR"),
+        name: env!("CARGO_PKG_NAME"),
+    })
 }
 #[cfg(test)]
 mod tests {
-
-    use api::Contract;
+    use api::{host, runtime_args, ApiError, Contract, RuntimeArgs};
 
     use super::*;
     #[test]
@@ -135,6 +115,13 @@ mod tests {
         exports::call(b"hello", b"world", b"asdf");
     }
 
+    #[test]
+    fn exports_accept_defaults() {
+        let env = host::TestEnv::new();
+        env.run(|| exports::echo("hey", 2));
+        assert_eq!(env.printed(), ["heyhey"]);
+    }
+
     #[test]
     fn compile_time_schema() {
         let schema = Flipper::schema();
@@ -145,11 +132,380 @@ mod tests {
         let s = serde_json::to_string_pretty(&schema).expect("foo");
         println!("{s}");
 
-        let mut flipper = Flipper::new();
+        let _flipper = Flipper::new();
         assert_eq!(Flipper::name(), "Flipper");
         // dbg!(&flipper);
         // flipper.flip(false, "Hello, world!".to_string());
         // let value = flipper.flag_value();
         // assert_eq!(value, true);
     }
+
+    fn flip_args() -> RuntimeArgs {
+        runtime_args! {
+            "argument1" => true,
+            "argument2" => "Hello, world!".to_string(),
+        }
+    }
+
+    #[test]
+    fn install_and_call() {
+        let address = Flipper::install().unwrap();
+
+        let flag_value: bool =
+            api::call_contract(address, "flag_value", Default::default()).unwrap();
+        assert!(!flag_value);
+
+        api::call_contract::<()>(address, "flip", flip_args()).unwrap();
+
+        let flag_value: bool =
+            api::call_contract(address, "flag_value", Default::default()).unwrap();
+        assert!(flag_value);
+
+        // State of the installed contract is separate from the one used outside of it.
+        assert!(!Flipper::new().flag_value());
+    }
+
+    #[test]
+    fn independent_envs() {
+        let env = host::TestEnv::new();
+        let other = host::TestEnv::new();
+        let address = env.deploy::<Flipper>().unwrap();
+        assert_eq!(other.deploy::<Flipper>().unwrap(), address);
+
+        env.call::<()>(address, "flip", flip_args()).unwrap();
+        assert!(env.call::<bool>(address, "flag_value", Default::default()).unwrap());
+        assert!(!other.call::<bool>(address, "flag_value", Default::default()).unwrap());
+
+        let storage = env.storage();
+        assert_eq!(storage.len(), 1);
+        assert_eq!(storage[0].contract, Some(address));
+        assert_eq!(storage[0].key, b"flag");
+        assert_eq!(storage[0].tag, <bool as api::CLTyped>::cl_type().tag());
+        assert_eq!(storage[0].value, [1]);
+        assert!(other.storage().is_empty());
+
+        // Clones share the chain, also across threads.
+        let shared = env.clone();
+        std::thread::spawn(move || shared.call::<()>(address, "flip", flip_args()).unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(env.read(Some(address), 0, b"flag").unwrap().1, [0]);
+        // The thread's own env was left untouched.
+        assert!(host::TestEnv::current().storage().is_empty());
+    }
+
+    #[test]
+    fn decodes_arguments_once() {
+        let env = host::TestEnv::new();
+        let address = env.deploy::<Flipper>().unwrap();
+        env.call::<()>(address, "flip", flip_args()).unwrap();
+
+        // Both arguments come out of a single copy of the input.
+        let input_len = borsh::to_vec(&flip_args()).unwrap().len();
+        let reports = env.run(host::take_gas_reports);
+        let copy_input = env.run(host::cost_table).copy_input;
+        assert_eq!(
+            reports[0].host_functions["copy_input"],
+            copy_input.base + copy_input.per_byte * input_len as u64
+        );
+    }
+
+    #[test]
+    fn dispatches_entry_points_by_name() {
+        let env = host::TestEnv::new();
+        env.run(Flipper::register);
+        assert_eq!(env.functions(), ["flag_value", "flip", "require_flag"]);
+
+        env.dispatch::<()>("flip", flip_args()).unwrap();
+        assert!(env.dispatch::<bool>("flag_value", RuntimeArgs::new()).unwrap());
+        assert_eq!(env.read(None, 0, b"flag").unwrap().1, [1]);
+        assert!(matches!(
+            env.dispatch::<()>("missing", RuntimeArgs::new()),
+            Err(ApiError::Host(host::Error::NotFound))
+        ));
+
+        // Reverts unwind out of `dispatch`, leaving the arguments of the env as they were.
+        env.dispatch::<()>("flip", flip_args()).unwrap();
+        env.set_args(runtime_args! { "outside" => true });
+        assert_eq!(
+            api::testing::call(|| env.dispatch::<()>("require_flag", flip_args())).unwrap_err(),
+            api::testing::Revert::Code(1)
+        );
+        assert_eq!(
+            env.run(api::named_args).unwrap(),
+            runtime_args! { "outside" => true }
+        );
+    }
+
+    /// Builds this contract for wasm, the way it gets deployed.
+    fn wasm() -> Vec<u8> {
+        let manifest_dir = env!("CARGO_MANIFEST_DIR");
+        let target_dir = format!("{manifest_dir}/../target/differential");
+        let status = std::process::Command::new(env!("CARGO"))
+            .args([
+                "build",
+                "--quiet",
+                "--release",
+                "--target",
+                "wasm32-unknown-unknown",
+            ])
+            .args(["--target-dir", &target_dir])
+            .current_dir(manifest_dir)
+            .status()
+            .expect("should run cargo");
+        assert!(status.success(), "wasm build failed");
+        std::fs::read(format!(
+            "{target_dir}/wasm32-unknown-unknown/release/test-contract.wasm"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn native_and_wasm_agree() {
+        use runner::differential::{self, Ending, Scenario};
+
+        fn call() {
+            let arg = |name: &str| api::get_named_arg::<Vec<u8>>(name).unwrap();
+            exports::call(&arg("arg1"), &arg("arg2"), &arg("arg3"));
+        }
+
+        // Natively, entry points are exported through their registered dispatchers.
+        fn flip() {
+            Flipper::register();
+            api::dispatch("flip").unwrap();
+        }
+
+        fn flag_value() {
+            Flipper::register();
+            api::dispatch("flag_value").unwrap();
+        }
+
+        let call_args = runtime_args! {
+            "arg1" => b"arg1".to_vec(),
+            "arg2" => b"arg2".to_vec(),
+            "arg3" => b"arg3".to_vec(),
+        };
+        // Address of the first contract created on a fresh chain.
+        let mut flipper = [0; 32];
+        flipper[7] = 1;
+
+        let scenario = Scenario::new()
+            .export("call", call_args, call)
+            .call(flipper, "flag_value", RuntimeArgs::new())
+            .call(flipper, "flip", flip_args())
+            .call(flipper, "flag_value", RuntimeArgs::new())
+            .call(flipper, "flip", RuntimeArgs::new())
+            .export("flag_value", RuntimeArgs::new(), flag_value)
+            .export("flip", flip_args(), flip)
+            .export("flag_value", RuntimeArgs::new(), flag_value);
+        let runner = runner::Runner::new(&wasm()).unwrap();
+        let outcomes = differential::compare(runner, &scenario).unwrap_or_else(|divergence| {
+            panic!("{divergence}");
+        });
+
+        assert_eq!(
+            outcomes[0].printed[0],
+            r#"arg1=Ok("arg1") arg2=Ok("arg2") arg3=Ok("arg3")"#
+        );
+        assert_eq!(outcomes[1].ending, Ending::Returned(Some(vec![0])));
+        assert_eq!(outcomes[3].ending, Ending::Returned(Some(vec![1])));
+        // Missing arguments abort the call.
+        assert_eq!(outcomes[4].ending, Ending::Aborted);
+        // Exported entry points run outside of the contract, on storage of their own.
+        assert_eq!(outcomes[5].ending, Ending::Returned(Some(vec![0])));
+        assert_eq!(outcomes[7].ending, Ending::Returned(Some(vec![1])));
+    }
+
+    #[derive(Contract)]
+    struct FlipperV2 {
+        flag: Value<bool>,
+    }
+
+    #[casper(entry_points)]
+    impl FlipperV2 {
+        pub fn set_flag(&mut self, value: bool) {
+            self.flag.set(value).unwrap();
+        }
+
+        pub fn flag_value(&self) -> bool {
+            self.flag.get().unwrap().unwrap_or_default()
+        }
+    }
+
+    #[derive(Contract)]
+    struct Vault {
+        deposited: Value<u64>,
+    }
+
+    #[casper(entry_points)]
+    impl Vault {
+        #[casper(payable)]
+        pub fn deposit(&mut self) {
+            let deposited = self.deposited.get().unwrap().unwrap_or_default();
+            self.deposited
+                .set(deposited + api::attached_value())
+                .unwrap();
+        }
+
+        pub fn deposited(&self) -> u64 {
+            self.deposited.get().unwrap().unwrap_or_default()
+        }
+    }
+
+    #[test]
+    fn payable_entry_points() {
+        let schema = Vault::schema();
+        assert!(schema.entry_points[0].payable);
+        assert!(!schema.entry_points[1].payable);
+
+        let vault = Vault::install().unwrap();
+        let account = host::Entity::Account([1; 32]);
+        host::set_context(host::Context {
+            caller: account,
+            ..Default::default()
+        });
+        host::set_balance(account, 100);
+
+        api::call_contract_with_value::<()>(vault, "deposit", Default::default(), 30).unwrap();
+        assert_eq!(host::balance(account), 70);
+        assert_eq!(host::balance(host::Entity::Contract(vault)), 30);
+
+        // Tokens attached to a non-payable entry point are rejected and given back.
+        assert!(matches!(
+            api::call_contract_with_value::<u64>(vault, "deposited", Default::default(), 10),
+            Err(ApiError::Host(host::Error::CalleeReverted))
+        ));
+        assert_eq!(host::balance(account), 70);
+
+        let deposited: u64 = api::call_contract(vault, "deposited", Default::default()).unwrap();
+        assert_eq!(deposited, 30);
+    }
+
+    #[derive(Contract)]
+    struct Greeter {
+        greetings: Value<u32>,
+    }
+
+    #[casper(entry_points)]
+    impl Greeter {
+        pub fn greet(&mut self, name: Option<String>, #[casper(default = 1)] times: u32) -> String {
+            let greetings = self.greetings.get().unwrap().unwrap_or_default();
+            self.greetings.set(greetings + times).unwrap();
+            format!("Hello, {}!", name.as_deref().unwrap_or("world")).repeat(times as usize)
+        }
+
+        pub fn shout(&self, text: &str, bytes: &[u8]) -> String {
+            format!("{}{}", text.to_uppercase(), "!".repeat(bytes.len()))
+        }
+
+        pub fn whisper(&self, text: &Option<String>) -> String {
+            text.as_deref().unwrap_or("psst").to_lowercase()
+        }
+    }
+
+    #[test]
+    fn optional_and_default_arguments() {
+        let schema = Greeter::schema();
+        let arguments = &schema.entry_points[0].arguments;
+        assert!(arguments[0].optional);
+        assert_eq!(arguments[0].default, None);
+        assert!(arguments[1].optional);
+        assert_eq!(arguments[1].default, Some("1"));
+
+        let greeter = Greeter::install().unwrap();
+        let greeting: String = api::call_contract(greeter, "greet", RuntimeArgs::new()).unwrap();
+        assert_eq!(greeting, "Hello, world!");
+
+        let args = runtime_args! { "name" => Some("Casper".to_string()), "times" => 2u32 };
+        let greeting: String = api::call_contract(greeter, "greet", args).unwrap();
+        assert_eq!(greeting, "Hello, Casper!Hello, Casper!");
+    }
+
+    #[test]
+    fn borrowed_optional_arguments() {
+        let schema = Greeter::schema();
+        assert!(schema.entry_points[2].arguments[0].optional);
+
+        let greeter = Greeter::install().unwrap();
+        let whisper: String = api::call_contract(greeter, "whisper", RuntimeArgs::new()).unwrap();
+        assert_eq!(whisper, "psst");
+        let args = runtime_args! { "text" => Some("HUSH".to_string()) };
+        let whisper: String = api::call_contract(greeter, "whisper", args).unwrap();
+        assert_eq!(whisper, "hush");
+    }
+
+    #[macros::entry_point]
+    fn repeat(
+        text: &str,
+        suffix: Option<String>,
+        #[casper(default = 2)] times: u32,
+    ) -> Result<(), ApiError> {
+        host::print(&format!("{}{}", text.repeat(times as usize), suffix.unwrap_or_default()));
+        Ok(())
+    }
+
+    #[test]
+    fn entry_point_wrapper_accepts_left_out_arguments() {
+        let env = host::TestEnv::new();
+        env.set_args(runtime_args! { "text" => "ha".to_string() });
+        env.run(repeat);
+        env.set_args(runtime_args! {
+            "text" => "ha".to_string(),
+            "suffix" => Some("!".to_string()),
+            "times" => 1u32,
+        });
+        env.run(repeat);
+        assert_eq!(env.printed(), ["haha", "ha!"]);
+    }
+
+    #[test]
+    fn borrowed_arguments() {
+        let schema = Greeter::schema();
+        let arguments = &schema.entry_points[1].arguments;
+        assert_eq!(arguments[0].ty, api::CLType::String);
+        assert_eq!(arguments[1].ty, <Vec<u8> as api::CLTyped>::cl_type());
+
+        let greeter = Greeter::install().unwrap();
+        let args = runtime_args! { "text" => "hey".to_string(), "bytes" => vec![0u8; 2] };
+        let shouted: String = api::call_contract(greeter, "shout", args).unwrap();
+        assert_eq!(shouted, "HEY!!");
+    }
+
+    #[test]
+    fn upgrade_keeps_state() {
+        let package = host::create_contract_package().unwrap();
+        let v1 = Flipper::install_version(&package).unwrap();
+        assert_eq!(v1.version, 1);
+        api::call_contract::<()>(package, "flip", flip_args()).unwrap();
+
+        let v2 = FlipperV2::install_version(&package).unwrap();
+        assert_eq!(v2.version, 2);
+        let flag_value: bool =
+            api::call_contract(package, "flag_value", Default::default()).unwrap();
+        assert!(flag_value);
+        assert!(matches!(
+            api::call_contract::<()>(package, "flip", flip_args()),
+            Err(ApiError::Host(host::Error::NotFound))
+        ));
+
+        // Older versions stay callable until disabled, and share the package storage.
+        api::call_contract::<()>(v1.address, "flip", flip_args()).unwrap();
+        host::disable_contract_version(&package, 1).unwrap();
+        assert!(matches!(
+            api::call_contract::<()>(v1.address, "flip", flip_args()),
+            Err(ApiError::Host(host::Error::ContractDisabled))
+        ));
+
+        let args = runtime_args! { "value" => true };
+        api::call_contract::<()>(package, "set_flag", args).unwrap();
+        let flag_value: bool =
+            api::call_contract(v2.address, "flag_value", Default::default()).unwrap();
+        assert!(flag_value);
+
+        host::disable_contract_version(&package, 2).unwrap();
+        assert!(matches!(
+            api::call_contract::<bool>(package, "flag_value", Default::default()),
+            Err(ApiError::Host(host::Error::ContractDisabled))
+        ));
+    }
 }
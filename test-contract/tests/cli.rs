@@ -0,0 +1,61 @@
+use std::process::Command;
+
+fn run(args: &[&str]) -> serde_json::Value {
+    let output = Command::new(env!("CARGO_BIN_EXE_test-contract"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn prints_schema() {
+    let schema = run(&["schema"]);
+    assert_eq!(schema["name"], "Flipper");
+    assert_eq!(schema["entry_points"][0]["name"], "flip");
+}
+
+#[test]
+fn calls_keep_state_in_file() {
+    let state = std::env::temp_dir().join(format!("{}-flipper.json", std::process::id()));
+    let state = state.to_str().unwrap();
+    let flip_args = r#"{"argument1": true, "argument2": "Hello, world!"}"#;
+
+    let flipped = run(&["call", state, "flip", flip_args]);
+    assert_eq!(flipped["returned"], serde_json::Value::Null);
+    assert_eq!(flipped["error"], serde_json::Value::Null);
+    assert_eq!(
+        flipped["printed"][0],
+        "flip argument1=true argument2=Hello, world!"
+    );
+    assert_eq!(flipped["storage"]["added"][0]["key"], hex::encode("flag"));
+    assert_eq!(flipped["storage"]["added"][0]["value"], "01");
+
+    let flag_value = run(&["call", state, "flag_value"]);
+    assert_eq!(flag_value["returned"], true);
+    assert_eq!(flag_value["storage"]["added"], serde_json::json!([]));
+
+    // The state file records where the contract lives, so it isn't installed again.
+    let saved: serde_json::Value = serde_json::from_slice(&std::fs::read(state).unwrap()).unwrap();
+    assert_eq!(saved["storage"][0]["contract"], saved["contract"]);
+
+    assert_eq!(
+        run(&["call", state, "require_flag"])["error"],
+        serde_json::Value::Null
+    );
+    run(&["call", state, "flip", flip_args]);
+    let required = run(&["call", state, "require_flag"]);
+    assert_eq!(required["returned"], serde_json::Value::Null);
+    assert_eq!(required["error"], "Code(1)");
+
+    std::fs::remove_file(state).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_test-contract"))
+        .args(["call", state, "flip", r#"{"argument1": "yes"}"#])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("invalid arguments"), "{stderr}");
+}